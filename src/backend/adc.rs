@@ -0,0 +1,106 @@
+use super::Adc;
+use crate::config::{Ads1115Config, SpiConfig};
+use anyhow::{Result, bail};
+use rppal::i2c::I2c;
+use rppal::spi::{Bus, Mode, SlaveSelect, Spi};
+use std::thread;
+use std::time::Duration;
+
+pub(super) fn spi(config: &SpiConfig) -> Result<Spi> {
+    let bus = match config.bus {
+        0 => Bus::Spi0,
+        1 => Bus::Spi1,
+        2 => Bus::Spi2,
+        3 => Bus::Spi3,
+        4 => Bus::Spi4,
+        5 => Bus::Spi5,
+        6 => Bus::Spi6,
+        bus => bail!("SPI bus must be between 0 and 6, got {bus}"),
+    };
+    let slave_select = match config.slave_select {
+        0 => SlaveSelect::Ss0,
+        1 => SlaveSelect::Ss1,
+        2 => SlaveSelect::Ss2,
+        slave_select => bail!("SPI slave select must be between 0 and 2, got {slave_select}"),
+    };
+    Ok(Spi::new(bus, slave_select, config.clock_hz, Mode::Mode0)?)
+}
+
+/// MCP3008 (10 bit) or MCP3208 (12 bit) 8 channel SPI ADC
+pub(super) struct Mcp3x08 {
+    spi: Spi,
+    twelve_bit: bool,
+}
+
+impl Mcp3x08 {
+    pub fn new(spi: Spi, twelve_bit: bool) -> Self {
+        Self { spi, twelve_bit }
+    }
+}
+
+impl Adc for Mcp3x08 {
+    fn read(&mut self, input: u8) -> Result<u16> {
+        // Start bit, single-ended mode and the channel, aligned so the reading ends the last two bytes
+        let request = if self.twelve_bit {
+            [0x06 | (input >> 2), (input & 0x03) << 6, 0]
+        } else {
+            [0x01, 0x80 | (input << 4), 0]
+        };
+        let mut response = [0; 3];
+        self.spi.transfer(&mut response, &request)?;
+
+        let mask = if self.twelve_bit { 0x0F } else { 0x03 };
+        Ok(((response[1] & mask) as u16) << 8 | response[2] as u16)
+    }
+}
+
+const ADS1115_CONVERSION: u8 = 0x00;
+const ADS1115_CONFIG: u8 = 0x01;
+// Set in the config register to start a conversion, and read back once it's done
+const ADS1115_OS: u16 = 1 << 15;
+
+/// ADS1115 4 channel I2C ADC, converting each reading on request
+pub(super) struct Ads1115 {
+    i2c: I2c,
+    // PGA, mode and data rate bits of the config register
+    config: u16,
+    conversion_time: Duration,
+}
+
+impl Ads1115 {
+    pub fn new(config: &Ads1115Config) -> Result<Self> {
+        let mut i2c = I2c::with_bus(config.bus)?;
+        i2c.set_slave_address(config.address)?;
+
+        let gain = config.gain_index().expect("Validated gain") as u16;
+        let data_rate = config.data_rate_index().expect("Validated data rate") as u16;
+        // Single-shot mode with the comparator disabled
+        let register = gain << 9 | 1 << 8 | data_rate << 5 | 0b11;
+        // The internal oscillator can run up to 10% slow
+        let conversion_time = Duration::from_secs_f64(1.1 / config.data_rate as f64);
+
+        Ok(Self { i2c, config: register, conversion_time })
+    }
+}
+
+impl Adc for Ads1115 {
+    fn read(&mut self, input: u8) -> Result<u16> {
+        // Single-ended, measuring the input against GND
+        let mux = (0b100 | input as u16) << 12;
+        self.i2c.smbus_write_word_swapped(ADS1115_CONFIG, ADS1115_OS | mux | self.config)?;
+
+        thread::sleep(self.conversion_time);
+        let mut polls = 0;
+        while self.i2c.smbus_read_word_swapped(ADS1115_CONFIG)? & ADS1115_OS == 0 {
+            polls += 1;
+            if polls == 10 {
+                bail!("ADS1115 conversion didn't finish");
+            }
+            thread::sleep(self.conversion_time / 10);
+        }
+
+        // Readings just below GND come out slightly negative
+        let reading = self.i2c.smbus_read_word_swapped(ADS1115_CONVERSION)? as i16;
+        Ok(reading.max(0) as u16)
+    }
+}
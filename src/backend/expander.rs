@@ -0,0 +1,262 @@
+use super::{Edge, EdgeCallback, Expander, InputPin, Level, Pull};
+use anyhow::{Result, bail};
+use rppal::i2c::I2c;
+use rppal::spi::Spi;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Register addresses with IOCON.BANK clear, where each B register follows its A register
+const IODIR: u8 = 0x00;
+const GPINTEN: u8 = 0x04;
+const INTCON: u8 = 0x08;
+const IOCON: u8 = 0x0A;
+const GPPU: u8 = 0x0C;
+pub(super) const GPIO: u8 = 0x12;
+
+// INTA and INTB both fire for either port
+const IOCON_MIRROR: u16 = 1 << 6;
+// Use the hardware address pins, only on the MCP23S17
+const IOCON_HAEN: u16 = 1 << 3;
+
+/// MCP23017 on an I2C bus
+pub(super) struct Mcp23017 {
+    i2c: I2c,
+}
+
+impl Mcp23017 {
+    pub fn new(bus: u8, address: u16) -> Result<Self> {
+        let mut i2c = I2c::with_bus(bus)?;
+        i2c.set_slave_address(address)?;
+        Ok(Self { i2c })
+    }
+}
+
+impl Expander for Mcp23017 {
+    fn read(&mut self, register: u8) -> Result<u16> {
+        let mut value = [0; 2];
+        self.i2c.write_read(&[register], &mut value)?;
+        Ok(u16::from_le_bytes(value))
+    }
+
+    fn write(&mut self, register: u8, value: u16) -> Result<()> {
+        let [a, b] = value.to_le_bytes();
+        self.i2c.write(&[register, a, b])?;
+        Ok(())
+    }
+}
+
+/// MCP23S17 on an SPI bus
+pub(super) struct Mcp23s17 {
+    spi: Spi,
+    // Write opcode, including the hardware address
+    opcode: u8,
+}
+
+impl Mcp23s17 {
+    pub fn new(spi: Spi, address: u8) -> Self {
+        Self { spi, opcode: 0x40 | address << 1 }
+    }
+}
+
+impl Expander for Mcp23s17 {
+    fn read(&mut self, register: u8) -> Result<u16> {
+        let mut response = [0; 4];
+        self.spi.transfer(&mut response, &[self.opcode | 1, register, 0, 0])?;
+        Ok(u16::from_le_bytes([response[2], response[3]]))
+    }
+
+    fn write(&mut self, register: u8, value: u16) -> Result<()> {
+        let [a, b] = value.to_le_bytes();
+        self.spi.write(&[self.opcode, register, a, b])?;
+        Ok(())
+    }
+}
+
+// Shared so it can be called once the chip is unlocked, letting it read other pins
+type SharedCallback = Arc<Mutex<EdgeCallback>>;
+
+struct Subscription {
+    callback: SharedCallback,
+    debounce: Option<Duration>,
+    // Level last passed to the callback
+    reported: Level,
+    last_edge: Option<Instant>,
+}
+
+struct Chip {
+    expander: Box<dyn Expander>,
+    // Bits of pins claimed as inputs, pulled up and interrupting
+    claimed: u16,
+    pull_ups: u16,
+    interrupts: u16,
+    // Last read GPIO register
+    levels: u16,
+    subscriptions: HashMap<u8, Subscription>,
+}
+
+impl Chip {
+    fn level(&self, pin: u8) -> Level {
+        if self.levels & 1 << pin != 0 { Level::High } else { Level::Low }
+    }
+
+    /// Read the pins, which also clears the interrupt, returning any edges to pass on
+    /// with [`dispatch`] after unlocking the chip
+    fn refresh(&mut self) -> Result<Vec<(SharedCallback, Edge)>> {
+        self.levels = self.expander.read(GPIO)?;
+        let now = Instant::now();
+        let mut edges = Vec::new();
+        for (&pin, subscription) in self.subscriptions.iter_mut() {
+            let level = if self.levels & 1 << pin != 0 { Level::High } else { Level::Low };
+            if level == subscription.reported {
+                continue;
+            }
+            if let (Some(debounce), Some(last_edge)) = (subscription.debounce, subscription.last_edge)
+                && now.duration_since(last_edge) < debounce
+            {
+                continue;
+            }
+            subscription.reported = level;
+            subscription.last_edge = Some(now);
+            edges.push((subscription.callback.clone(), if level == Level::High { Edge::Rising } else { Edge::Falling }));
+        }
+        Ok(edges)
+    }
+}
+
+fn dispatch(edges: Vec<(SharedCallback, Edge)>) {
+    for (callback, edge) in edges {
+        (callback.lock().unwrap())(edge);
+    }
+}
+
+/// An expander's pins, read when its interrupt output fires
+pub(crate) struct ExpanderPins {
+    name: String,
+    chip: Arc<Mutex<Chip>>,
+    // Keep alive for interrupt
+    _interrupt: Box<dyn InputPin>,
+}
+
+impl fmt::Debug for ExpanderPins {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExpanderPins").field("name", &self.name).finish()
+    }
+}
+
+impl ExpanderPins {
+    /// Set up `expander` to pull `interrupt`, which must be pulled up, low on any change
+    pub fn new(name: &str, mut expander: Box<dyn Expander>, mut interrupt: Box<dyn InputPin>) -> Result<Self> {
+        // Both bytes address the same register
+        expander.write(IOCON, (IOCON_MIRROR | IOCON_HAEN) * 0x0101)?;
+        expander.write(IODIR, 0xFFFF)?;
+        expander.write(GPPU, 0)?;
+        expander.write(GPINTEN, 0)?;
+        // Interrupt on any change rather than against a default value
+        expander.write(INTCON, 0)?;
+        let levels = expander.read(GPIO)?;
+
+        let chip = Arc::new(Mutex::new(Chip {
+            expander,
+            claimed: 0,
+            pull_ups: 0,
+            interrupts: 0,
+            levels,
+            subscriptions: HashMap::new(),
+        }));
+        let interrupt_chip = chip.clone();
+        interrupt.subscribe(None, Box::new(move |edge| {
+            // A failed read leaves the interrupt pending, and no later change would pull
+            // it low again, so retry a couple of times
+            if edge == Edge::Falling {
+                let edges = {
+                    let mut chip = interrupt_chip.lock().unwrap();
+                    (0..3).find_map(|_| chip.refresh().ok())
+                };
+                dispatch(edges.unwrap_or_default());
+            }
+        }))?;
+
+        Ok(Self { name: name.to_string(), chip, _interrupt: interrupt })
+    }
+
+    pub fn input(&self, pin: u8, pull: Pull) -> Result<Box<dyn InputPin>> {
+        let mut chip = self.chip.lock().unwrap();
+        let bit = 1 << pin;
+        if chip.claimed & bit != 0 {
+            bail!("Expander pin {}:{pin} is already in use", self.name);
+        }
+
+        let pull_ups = match pull {
+            Pull::Up => chip.pull_ups | bit,
+            Pull::Off => chip.pull_ups & !bit,
+            Pull::Down => bail!("Expander pin {}:{pin} can't be pulled down", self.name),
+        };
+        chip.expander.write(GPPU, pull_ups)?;
+        chip.pull_ups = pull_ups;
+        chip.claimed |= bit;
+        let edges = chip.refresh()?;
+        drop(chip);
+        dispatch(edges);
+
+        Ok(Box::new(ExpanderInputPin { pin, chip: self.chip.clone() }))
+    }
+}
+
+struct ExpanderInputPin {
+    pin: u8,
+    chip: Arc<Mutex<Chip>>,
+}
+
+impl fmt::Debug for ExpanderInputPin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExpanderInputPin").field("pin", &self.pin).finish()
+    }
+}
+
+impl InputPin for ExpanderInputPin {
+    /// Pin on the expander, 0-15
+    fn pin(&self) -> u8 {
+        self.pin
+    }
+
+    fn read(&self) -> Level {
+        let mut chip = self.chip.lock().unwrap();
+        // A subscribed pin is kept up to date by the interrupt, and its callback may read
+        // it, which mustn't start another refresh
+        if chip.subscriptions.contains_key(&self.pin) {
+            return chip.level(self.pin);
+        }
+        // Refreshing rather than reading directly, so edges on other pins aren't lost
+        // when this clears the interrupt. Falls back to the last reading on failure.
+        let edges = chip.refresh().unwrap_or_default();
+        let level = chip.level(self.pin);
+        drop(chip);
+        dispatch(edges);
+        level
+    }
+
+    fn subscribe(&mut self, debounce: Option<Duration>, callback: EdgeCallback) -> Result<()> {
+        let mut chip = self.chip.lock().unwrap();
+        let interrupts = chip.interrupts | 1 << self.pin;
+        chip.expander.write(GPINTEN, interrupts)?;
+        chip.interrupts = interrupts;
+
+        let reported = chip.level(self.pin);
+        chip.subscriptions.insert(self.pin, Subscription { callback: Arc::new(Mutex::new(callback)), debounce, reported, last_edge: None });
+        Ok(())
+    }
+}
+
+impl Drop for ExpanderInputPin {
+    fn drop(&mut self) {
+        let mut chip = self.chip.lock().unwrap();
+        let bit = 1 << self.pin;
+        chip.claimed &= !bit;
+        chip.interrupts &= !bit;
+        chip.subscriptions.remove(&self.pin);
+        let interrupts = chip.interrupts;
+        let _ = chip.expander.write(GPINTEN, interrupts);
+    }
+}
@@ -0,0 +1,307 @@
+use super::expander::GPIO;
+use super::{Adc, Edge, EdgeCallback, Expander, GpioBackend, InputPin, Level, OutputPin, Pull, PwmPin, TristatePin};
+use crate::config::{AdcConfig, ExpanderConfig};
+use anyhow::{Result, bail};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Default)]
+struct MockPinState {
+    level: Option<Level>,
+    duty_cycle: Option<f64>,
+    // Level a tristate pin drives, none while floating
+    driven: Option<Level>,
+    // Pins wired to this one, e.g. through a pressed matrix key
+    connected: Vec<u8>,
+    claimed: bool,
+    // Called without the pins locked, so it can read other pins
+    callback: Option<Arc<Mutex<EdgeCallback>>>,
+}
+
+impl fmt::Debug for MockPinState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MockPinState")
+            .field("level", &self.level)
+            .field("duty_cycle", &self.duty_cycle)
+            .field("driven", &self.driven)
+            .field("connected", &self.connected)
+            .field("claimed", &self.claimed)
+            .field("subscribed", &self.callback.is_some())
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+struct MockExpanderState {
+    // GPIO register, high until set
+    levels: u16,
+    // Native pin pulsed low when a level changes, once the expander is claimed
+    interrupt: Option<u8>,
+}
+
+impl Default for MockExpanderState {
+    fn default() -> Self {
+        Self { levels: 0xFFFF, interrupt: None }
+    }
+}
+
+/// In-memory GPIO for running without a Pi. Clones share the same pins, so a
+/// test can keep one handle and script transitions with [`MockBackend::set_level`].
+#[derive(Debug, Clone, Default)]
+pub struct MockBackend {
+    pins: Arc<Mutex<HashMap<u8, MockPinState>>>,
+    // Raw readings by ADC name and input
+    analog: Arc<Mutex<HashMap<(String, u8), u16>>>,
+    expanders: Arc<Mutex<HashMap<String, MockExpanderState>>>,
+}
+
+impl MockBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Drive an input to `level`, firing its edge callback if the level changed.
+    /// Pins that have not been claimed yet start at this level when they are.
+    pub fn set_level(&self, pin: u8, level: Level) {
+        let callback = {
+            let mut pins = self.pins.lock().unwrap();
+            let state = pins.entry(pin).or_default();
+            let previous = state.level.replace(level);
+
+            if previous.is_none() || previous == Some(level) {
+                return;
+            }
+            state.callback.clone()
+        };
+
+        if let Some(callback) = callback {
+            (callback.lock().unwrap())(if level == Level::High { Edge::Rising } else { Edge::Falling });
+        }
+    }
+
+    pub fn level(&self, pin: u8) -> Option<Level> {
+        self.pins.lock().unwrap().get(&pin).and_then(|s| s.level)
+    }
+
+    /// Wire `a` and `b` together or apart. An input reads the level driven by a tristate
+    /// pin connected to it, or its own level if none are driving.
+    pub fn set_connected(&self, a: u8, b: u8, connected: bool) {
+        let mut pins = self.pins.lock().unwrap();
+        for (pin, other) in [(a, b), (b, a)] {
+            let state = pins.entry(pin).or_default();
+            state.connected.retain(|&p| p != other);
+            if connected {
+                state.connected.push(other);
+            }
+        }
+    }
+
+    /// Set the raw reading of `input` on the ADC called `adc`
+    pub fn set_analog(&self, adc: &str, input: u8, value: u16) {
+        self.analog.lock().unwrap().insert((adc.to_string(), input), value);
+    }
+
+    /// Drive pin `pin` of the expander called `chip` to `level`, pulsing its interrupt
+    /// pin if the level changed
+    pub fn set_expander_level(&self, chip: &str, pin: u8, level: Level) {
+        let mut expanders = self.expanders.lock().unwrap();
+        let state = expanders.entry(chip.to_string()).or_default();
+        let levels = match level {
+            Level::High => state.levels | 1 << pin,
+            Level::Low => state.levels & !(1 << pin),
+        };
+        let interrupt = state.interrupt.filter(|_| levels != state.levels);
+        state.levels = levels;
+        drop(expanders);
+
+        if let Some(interrupt) = interrupt {
+            self.set_level(interrupt, Level::Low);
+            self.set_level(interrupt, Level::High);
+        }
+    }
+
+    /// Duty cycle of a PWM output
+    pub fn duty_cycle(&self, pin: u8) -> Option<f64> {
+        self.pins.lock().unwrap().get(&pin).and_then(|s| s.duty_cycle)
+    }
+}
+
+impl GpioBackend for MockBackend {
+    fn input(&mut self, pin: u8, pull: Pull) -> Result<Box<dyn InputPin>> {
+        let mut pins = self.pins.lock().unwrap();
+        let state = pins.entry(pin).or_default();
+        if state.claimed {
+            bail!("Mock pin {pin} is already in use");
+        }
+        state.claimed = true;
+        state.level.get_or_insert(match pull {
+            Pull::Up => Level::High,
+            Pull::Down | Pull::Off => Level::Low,
+        });
+
+        Ok(Box::new(MockInputPin { pin, pins: self.pins.clone() }))
+    }
+
+    fn output(&mut self, pin: u8) -> Result<Box<dyn OutputPin>> {
+        let mut pins = self.pins.lock().unwrap();
+        let state = pins.entry(pin).or_default();
+        if state.claimed {
+            bail!("Mock pin {pin} is already in use");
+        }
+        state.claimed = true;
+        state.level = Some(Level::Low);
+
+        Ok(Box::new(MockOutputPin { pin, pins: self.pins.clone() }))
+    }
+
+    fn tristate(&mut self, pin: u8) -> Result<Box<dyn TristatePin>> {
+        let mut pins = self.pins.lock().unwrap();
+        let state = pins.entry(pin).or_default();
+        if state.claimed {
+            bail!("Mock pin {pin} is already in use");
+        }
+        state.claimed = true;
+        state.driven = None;
+
+        Ok(Box::new(MockOutputPin { pin, pins: self.pins.clone() }))
+    }
+
+    fn pwm_output(&mut self, pin: u8, _frequency: f64, _hardware_channel: Option<u8>) -> Result<Box<dyn PwmPin>> {
+        let mut pins = self.pins.lock().unwrap();
+        let state = pins.entry(pin).or_default();
+        if state.claimed {
+            bail!("Mock pin {pin} is already in use");
+        }
+        state.claimed = true;
+        state.duty_cycle = Some(0.0);
+
+        Ok(Box::new(MockOutputPin { pin, pins: self.pins.clone() }))
+    }
+
+    fn adc(&mut self, config: &AdcConfig) -> Result<Box<dyn Adc>> {
+        Ok(Box::new(MockAdc { name: config.name.clone(), analog: self.analog.clone() }))
+    }
+
+    fn expander(&mut self, config: &ExpanderConfig) -> Result<Box<dyn Expander>> {
+        self.expanders.lock().unwrap().entry(config.name.clone()).or_default().interrupt = Some(config.interrupt);
+        Ok(Box::new(MockExpander { name: config.name.clone(), expanders: self.expanders.clone() }))
+    }
+}
+
+/// Reads levels set with [`MockBackend::set_expander_level`] from the GPIO register,
+/// other registers read 0 and writes are ignored
+struct MockExpander {
+    name: String,
+    expanders: Arc<Mutex<HashMap<String, MockExpanderState>>>,
+}
+
+impl Expander for MockExpander {
+    fn read(&mut self, register: u8) -> Result<u16> {
+        Ok(if register == GPIO { self.expanders.lock().unwrap()[&self.name].levels } else { 0 })
+    }
+
+    fn write(&mut self, _register: u8, _value: u16) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads values set with [`MockBackend::set_analog`], 0 until set
+struct MockAdc {
+    name: String,
+    analog: Arc<Mutex<HashMap<(String, u8), u16>>>,
+}
+
+impl Adc for MockAdc {
+    fn read(&mut self, input: u8) -> Result<u16> {
+        Ok(self.analog.lock().unwrap().get(&(self.name.clone(), input)).copied().unwrap_or(0))
+    }
+}
+
+#[derive(Debug)]
+struct MockInputPin {
+    pin: u8,
+    pins: Arc<Mutex<HashMap<u8, MockPinState>>>,
+}
+
+impl InputPin for MockInputPin {
+    fn pin(&self) -> u8 {
+        self.pin
+    }
+
+    fn read(&self) -> Level {
+        let pins = self.pins.lock().unwrap();
+        let state = &pins[&self.pin];
+        state.connected.iter().find_map(|pin| pins.get(pin)?.driven).or(state.level).unwrap_or(Level::Low)
+    }
+
+    fn subscribe(&mut self, _debounce: Option<Duration>, callback: EdgeCallback) -> Result<()> {
+        if let Some(state) = self.pins.lock().unwrap().get_mut(&self.pin) {
+            state.callback = Some(Arc::new(Mutex::new(callback)));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MockInputPin {
+    fn drop(&mut self) {
+        if let Some(state) = self.pins.lock().unwrap().get_mut(&self.pin) {
+            state.claimed = false;
+            state.callback = None;
+        }
+    }
+}
+
+/// Writes to the shared pin state, so tests can check it with [`MockBackend::level`]
+/// or [`MockBackend::duty_cycle`]
+#[derive(Debug)]
+struct MockOutputPin {
+    pin: u8,
+    pins: Arc<Mutex<HashMap<u8, MockPinState>>>,
+}
+
+impl OutputPin for MockOutputPin {
+    fn pin(&self) -> u8 {
+        self.pin
+    }
+
+    fn write(&mut self, level: Level) {
+        if let Some(state) = self.pins.lock().unwrap().get_mut(&self.pin) {
+            state.level = Some(level);
+        }
+    }
+}
+
+impl PwmPin for MockOutputPin {
+    fn pin(&self) -> u8 {
+        self.pin
+    }
+
+    fn set_duty_cycle(&mut self, duty_cycle: f64) {
+        if let Some(state) = self.pins.lock().unwrap().get_mut(&self.pin) {
+            state.duty_cycle = Some(duty_cycle.clamp(0.0, 1.0));
+        }
+    }
+}
+
+impl TristatePin for MockOutputPin {
+    fn pin(&self) -> u8 {
+        self.pin
+    }
+
+    fn set(&mut self, level: Option<Level>) {
+        if let Some(state) = self.pins.lock().unwrap().get_mut(&self.pin) {
+            state.driven = level;
+        }
+    }
+}
+
+impl Drop for MockOutputPin {
+    fn drop(&mut self) {
+        if let Some(state) = self.pins.lock().unwrap().get_mut(&self.pin) {
+            state.claimed = false;
+            state.driven = None;
+        }
+    }
+}
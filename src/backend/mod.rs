@@ -0,0 +1,144 @@
+use crate::config::{AdcConfig, ExpanderConfig};
+use anyhow::Result;
+use serde::Deserialize;
+use std::fmt::Debug;
+use std::time::Duration;
+
+mod adc;
+mod expander;
+#[cfg(feature = "mock")]
+mod mock;
+mod rpi;
+
+#[cfg(feature = "mock")]
+pub use mock::MockBackend;
+pub use rpi::RppalBackend;
+pub(crate) use expander::ExpanderPins;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Low,
+    High,
+}
+
+impl Level {
+    pub fn inverted(self) -> Level {
+        match self {
+            Level::Low => Level::High,
+            Level::High => Level::Low,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edge {
+    Rising,
+    Falling,
+}
+
+impl Edge {
+    pub fn inverted(self) -> Edge {
+        match self {
+            Edge::Rising => Edge::Falling,
+            Edge::Falling => Edge::Rising,
+        }
+    }
+}
+
+/// Internal pull resistor
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pull {
+    #[default]
+    Up,
+    Down,
+    /// No pull, for externally pulled inputs
+    Off,
+}
+
+pub type EdgeCallback = Box<dyn FnMut(Edge) + Send>;
+
+/// A configured GPIO input
+pub trait InputPin: Debug + Send + Sync {
+    fn pin(&self) -> u8;
+
+    fn read(&self) -> Level;
+
+    /// Call `callback` on every rising and falling edge, replacing any previous subscription
+    fn subscribe(&mut self, debounce: Option<Duration>, callback: EdgeCallback) -> Result<()>;
+}
+
+/// A configured GPIO output
+pub trait OutputPin: Debug + Send {
+    fn pin(&self) -> u8;
+
+    fn write(&mut self, level: Level);
+}
+
+/// A GPIO output that can also float, so the rows of a button matrix that aren't
+/// selected don't fight the selected one through pressed keys
+pub trait TristatePin: Debug + Send {
+    fn pin(&self) -> u8;
+
+    /// Drive the pin to `level`, or float it with `None`
+    fn set(&mut self, level: Option<Level>);
+}
+
+/// A GPIO output with a variable duty cycle
+pub trait PwmPin: Debug + Send {
+    fn pin(&self) -> u8;
+
+    /// Fraction of each period the output is high, from 0.0 to 1.0
+    fn set_duty_cycle(&mut self, duty_cycle: f64);
+}
+
+/// An analog-to-digital converter
+pub trait Adc: Send {
+    /// Raw reading of `input`, from 0 to the ADC's full scale
+    fn read(&mut self, input: u8) -> Result<u16>;
+}
+
+/// Register access to an MCP23017 or MCP23S17, with both ports of a register read and
+/// written together, port A in the low byte
+pub trait Expander: Send {
+    fn read(&mut self, register: u8) -> Result<u16>;
+
+    fn write(&mut self, register: u8, value: u16) -> Result<()>;
+}
+
+/// Source of GPIO pins and ADCs, e.g. the Pi's header or an in-memory mock
+pub trait GpioBackend: Send {
+    fn input(&mut self, pin: u8, pull: Pull) -> Result<Box<dyn InputPin>>;
+
+    /// Claim `pin` as an output, starting low
+    fn output(&mut self, pin: u8) -> Result<Box<dyn OutputPin>>;
+
+    /// Claim `pin` as an output that starts floating
+    fn tristate(&mut self, pin: u8) -> Result<Box<dyn TristatePin>>;
+
+    /// Claim `pin` as a PWM output at `frequency` hz, starting at a duty cycle of 0. Uses
+    /// the hardware PWM `hardware_channel` if given, which must already be routed to `pin`.
+    fn pwm_output(&mut self, pin: u8, frequency: f64, hardware_channel: Option<u8>) -> Result<Box<dyn PwmPin>>;
+
+    fn adc(&mut self, config: &AdcConfig) -> Result<Box<dyn Adc>>;
+
+    fn expander(&mut self, config: &ExpanderConfig) -> Result<Box<dyn Expander>>;
+}
+
+/// Wraps an active-low input so that it reads high while active
+#[derive(Debug)]
+pub struct Inverted(pub Box<dyn InputPin>);
+
+impl InputPin for Inverted {
+    fn pin(&self) -> u8 {
+        self.0.pin()
+    }
+
+    fn read(&self) -> Level {
+        self.0.read().inverted()
+    }
+
+    fn subscribe(&mut self, debounce: Option<Duration>, mut callback: EdgeCallback) -> Result<()> {
+        self.0.subscribe(debounce, Box::new(move |edge| callback(edge.inverted())))
+    }
+}
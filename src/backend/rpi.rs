@@ -0,0 +1,180 @@
+use super::adc::{self, Ads1115, Mcp3x08};
+use super::expander::{Mcp23017, Mcp23s17};
+use super::{Adc, Edge, EdgeCallback, Expander, GpioBackend, InputPin, Level, OutputPin, Pull, PwmPin, TristatePin};
+use crate::config::{AdcConfig, AdcKind, ExpanderConfig, ExpanderKind};
+use anyhow::{Result, bail};
+use rppal::gpio::{self, Gpio, Mode, Trigger};
+use rppal::pwm::{self, Polarity, Pwm};
+use std::time::Duration;
+
+impl From<Level> for gpio::Level {
+    fn from(level: Level) -> Self {
+        match level {
+            Level::Low => gpio::Level::Low,
+            Level::High => gpio::Level::High,
+        }
+    }
+}
+
+impl From<gpio::Level> for Level {
+    fn from(level: gpio::Level) -> Self {
+        match level {
+            gpio::Level::Low => Level::Low,
+            gpio::Level::High => Level::High,
+        }
+    }
+}
+
+/// Raspberry Pi GPIO through rppal
+pub struct RppalBackend {
+    gpio: Gpio,
+}
+
+impl RppalBackend {
+    pub fn new() -> Result<Self> {
+        Ok(Self { gpio: Gpio::new()? })
+    }
+}
+
+impl GpioBackend for RppalBackend {
+    fn input(&mut self, pin: u8, pull: Pull) -> Result<Box<dyn InputPin>> {
+        let gpio_pin = self.gpio.get(pin)?;
+        let mut input = match pull {
+            Pull::Up => gpio_pin.into_input_pullup(),
+            Pull::Down => gpio_pin.into_input_pulldown(),
+            Pull::Off => gpio_pin.into_input(),
+        };
+        input.set_reset_on_drop(false);
+        Ok(Box::new(RppalInputPin(input)))
+    }
+
+    fn output(&mut self, pin: u8) -> Result<Box<dyn OutputPin>> {
+        Ok(Box::new(RppalOutputPin(self.gpio.get(pin)?.into_output_low())))
+    }
+
+    fn tristate(&mut self, pin: u8) -> Result<Box<dyn TristatePin>> {
+        Ok(Box::new(RppalTristatePin(self.gpio.get(pin)?.into_io(Mode::Input))))
+    }
+
+    fn pwm_output(&mut self, pin: u8, frequency: f64, hardware_channel: Option<u8>) -> Result<Box<dyn PwmPin>> {
+        let Some(channel) = hardware_channel else {
+            let mut output = self.gpio.get(pin)?.into_output_low();
+            output.set_pwm_frequency(frequency, 0.0)?;
+            return Ok(Box::new(SoftwarePwmPin { output, frequency }));
+        };
+
+        let channel = match channel {
+            0 => pwm::Channel::Pwm0,
+            1 => pwm::Channel::Pwm1,
+            2 => pwm::Channel::Pwm2,
+            3 => pwm::Channel::Pwm3,
+            _ => bail!("Hardware PWM channel must be between 0 and 3, got {channel}"),
+        };
+        let pwm = Pwm::with_frequency(channel, frequency, 0.0, Polarity::Normal, true)?;
+        Ok(Box::new(HardwarePwmPin { pin, pwm }))
+    }
+
+    fn adc(&mut self, config: &AdcConfig) -> Result<Box<dyn Adc>> {
+        Ok(match &config.kind {
+            AdcKind::Mcp3008(spi) => Box::new(Mcp3x08::new(adc::spi(spi)?, false)),
+            AdcKind::Mcp3208(spi) => Box::new(Mcp3x08::new(adc::spi(spi)?, true)),
+            AdcKind::Ads1115(ads1115) => Box::new(Ads1115::new(ads1115)?),
+        })
+    }
+
+    fn expander(&mut self, config: &ExpanderConfig) -> Result<Box<dyn Expander>> {
+        Ok(match &config.kind {
+            ExpanderKind::Mcp23017 { bus, address } => Box::new(Mcp23017::new(*bus, *address)?),
+            ExpanderKind::Mcp23s17(mcp23s17) => Box::new(Mcp23s17::new(adc::spi(&mcp23s17.spi)?, mcp23s17.address)),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct RppalInputPin(gpio::InputPin);
+
+impl InputPin for RppalInputPin {
+    fn pin(&self) -> u8 {
+        self.0.pin()
+    }
+
+    fn read(&self) -> Level {
+        self.0.read().into()
+    }
+
+    fn subscribe(&mut self, debounce: Option<Duration>, mut callback: EdgeCallback) -> Result<()> {
+        self.0.set_async_interrupt(Trigger::Both, debounce, move |event| {
+            match event.trigger {
+                Trigger::RisingEdge => callback(Edge::Rising),
+                Trigger::FallingEdge => callback(Edge::Falling),
+                _ => {}
+            }
+        })?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct RppalOutputPin(gpio::OutputPin);
+
+impl OutputPin for RppalOutputPin {
+    fn pin(&self) -> u8 {
+        self.0.pin()
+    }
+
+    fn write(&mut self, level: Level) {
+        self.0.write(level.into());
+    }
+}
+
+#[derive(Debug)]
+struct RppalTristatePin(gpio::IoPin);
+
+impl TristatePin for RppalTristatePin {
+    fn pin(&self) -> u8 {
+        self.0.pin()
+    }
+
+    fn set(&mut self, level: Option<Level>) {
+        match level {
+            // Set the level before switching to an output so the pin doesn't glitch
+            Some(level) => {
+                self.0.write(level.into());
+                self.0.set_mode(Mode::Output);
+            }
+            None => self.0.set_mode(Mode::Input),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SoftwarePwmPin {
+    output: gpio::OutputPin,
+    frequency: f64,
+}
+
+impl PwmPin for SoftwarePwmPin {
+    fn pin(&self) -> u8 {
+        self.output.pin()
+    }
+
+    fn set_duty_cycle(&mut self, duty_cycle: f64) {
+        let _ = self.output.set_pwm_frequency(self.frequency, duty_cycle);
+    }
+}
+
+#[derive(Debug)]
+struct HardwarePwmPin {
+    pin: u8,
+    pwm: Pwm,
+}
+
+impl PwmPin for HardwarePwmPin {
+    fn pin(&self) -> u8 {
+        self.pin
+    }
+
+    fn set_duty_cycle(&mut self, duty_cycle: f64) {
+        let _ = self.pwm.set_duty_cycle(duty_cycle);
+    }
+}
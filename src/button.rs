@@ -0,0 +1,428 @@
+use crate::config::{ButtonAction, ButtonMessage, ButtonMode, Config, KeyConfig, NoteRelease};
+use crate::mapping::{Active, Mappings};
+use crate::midi::{self, Incoming};
+use crate::state::StateFile;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// A [`ButtonMessage`] resolved against the default channel, with a zero-based channel
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum ButtonOutput {
+    Cc {
+        channel: u8,
+        cc: u8,
+        on_value: u8,
+        off_value: u8,
+    },
+    Note {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+        release: NoteRelease,
+    },
+}
+
+impl ButtonOutput {
+    pub fn new(message: &ButtonMessage, default_channel: u8) -> Self {
+        let channel = message.channel.unwrap_or(default_channel) - 1;
+        match (message.cc, message.note) {
+            (_, Some(note)) => ButtonOutput::Note { channel, note, velocity: message.velocity, release: message.note_release },
+            (cc, None) => ButtonOutput::Cc {
+                channel,
+                cc: cc.expect("Validated button message has a cc or note"),
+                on_value: message.on_value,
+                off_value: message.off_value,
+            },
+        }
+    }
+
+    /// Message for the button turning on
+    pub fn on(&self) -> [u8; 3] {
+        match *self {
+            ButtonOutput::Cc { channel, cc, on_value, .. } => midi::control_change(channel, cc, on_value),
+            ButtonOutput::Note { channel, note, velocity, .. } => midi::note_on(channel, note, velocity),
+        }
+    }
+
+    /// Message for the button turning off
+    pub fn off(&self) -> [u8; 3] {
+        match *self {
+            ButtonOutput::Cc { channel, cc, off_value, .. } => midi::control_change(channel, cc, off_value),
+            ButtonOutput::Note { channel, note, release: NoteRelease::NoteOff, .. } => midi::note_off(channel, note, 0),
+            ButtonOutput::Note { channel, note, release: NoteRelease::ZeroVelocity, .. } => midi::note_on(channel, note, 0),
+        }
+    }
+
+    /// Whether an incoming message turns this output on or off, if it's for the same CC or note
+    pub fn matches(&self, incoming: Incoming) -> Option<bool> {
+        match (*self, incoming) {
+            (ButtonOutput::Cc { channel, cc, off_value, .. }, Incoming::ControlChange { channel: c, cc: n, value })
+                if (c, n) == (channel, cc) =>
+            {
+                Some(value != off_value)
+            }
+            (ButtonOutput::Note { channel, note, .. }, Incoming::Note { channel: c, note: n, velocity })
+                if (c, n) == (channel, note) =>
+            {
+                Some(velocity > 0)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Gesture {
+    Short,
+    Long,
+    DoubleTap,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum GestureState {
+    Idle,
+    // Pressed, not yet known to be a short or long press
+    Pressed { since: Instant },
+    // Released after a short press, waiting for a second tap
+    Released { at: Instant },
+    // A long press or double tap that ends on release
+    Holding(Gesture),
+}
+
+/// Turns presses and releases into gestures. Every gesture is reported as started
+/// (`true`) and later ended (`false`), short presses both at once.
+#[derive(Debug)]
+pub(crate) struct GestureDetector {
+    long_press: Option<Duration>,
+    double_tap: Option<Duration>,
+    state: GestureState,
+}
+
+impl GestureDetector {
+    pub fn new(long_press: Option<Duration>, double_tap: Option<Duration>) -> Self {
+        Self { long_press, double_tap, state: GestureState::Idle }
+    }
+
+    pub fn press(&mut self, now: Instant) -> Vec<(Gesture, bool)> {
+        match self.state {
+            GestureState::Idle => {
+                self.state = GestureState::Pressed { since: now };
+                Vec::new()
+            }
+            // Too late for a double tap, but the short press may not have expired yet
+            GestureState::Released { .. } if self.deadline().is_some_and(|deadline| now >= deadline) => {
+                self.state = GestureState::Pressed { since: now };
+                vec![(Gesture::Short, true), (Gesture::Short, false)]
+            }
+            GestureState::Released { .. } => {
+                self.state = GestureState::Holding(Gesture::DoubleTap);
+                vec![(Gesture::DoubleTap, true)]
+            }
+            GestureState::Pressed { .. } | GestureState::Holding(_) => Vec::new(),
+        }
+    }
+
+    pub fn release(&mut self, now: Instant) -> Vec<(Gesture, bool)> {
+        match self.state {
+            GestureState::Pressed { .. } if self.double_tap.is_some() => {
+                self.state = GestureState::Released { at: now };
+                Vec::new()
+            }
+            GestureState::Pressed { .. } => {
+                self.state = GestureState::Idle;
+                vec![(Gesture::Short, true), (Gesture::Short, false)]
+            }
+            GestureState::Holding(gesture) => {
+                self.state = GestureState::Idle;
+                vec![(gesture, false)]
+            }
+            GestureState::Idle | GestureState::Released { .. } => Vec::new(),
+        }
+    }
+
+    /// When [`GestureDetector::expire`] next needs calling
+    pub fn deadline(&self) -> Option<Instant> {
+        match self.state {
+            GestureState::Pressed { since } => self.long_press.map(|long_press| since + long_press),
+            GestureState::Released { at } => self.double_tap.map(|double_tap| at + double_tap),
+            GestureState::Idle | GestureState::Holding(_) => None,
+        }
+    }
+
+    pub fn expire(&mut self, now: Instant) -> Vec<(Gesture, bool)> {
+        if self.deadline().is_none_or(|deadline| now < deadline) {
+            return Vec::new();
+        }
+
+        match self.state {
+            GestureState::Pressed { .. } => {
+                self.state = GestureState::Holding(Gesture::Long);
+                vec![(Gesture::Long, true)]
+            }
+            GestureState::Released { .. } => {
+                self.state = GestureState::Idle;
+                vec![(Gesture::Short, true), (Gesture::Short, false)]
+            }
+            GestureState::Idle | GestureState::Holding(_) => Vec::new(),
+        }
+    }
+}
+
+/// A shift key resolved to a layer index
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Shift {
+    pub layer: usize,
+    pub latching: bool,
+}
+
+/// What a button does instead of sending MIDI, resolved to layer and bank indices
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Action {
+    Shift(Shift),
+    NextBank,
+    PrevBank,
+    SelectBank(usize),
+}
+
+#[derive(Debug)]
+struct Mapping {
+    output: ButtonOutput,
+    // Whether a toggle is currently on
+    latched: bool,
+    // Key in the state file
+    key: String,
+}
+
+/// A configured button
+#[derive(Debug)]
+pub(crate) struct Button {
+    pub restore: bool,
+    pub action: Option<Action>,
+    mode: ButtonMode,
+    mappings: Mappings<Mapping>,
+    // Mapping selected by the last press, so releases go to the same place
+    slot: usize,
+    gestures: Option<GestureDetector>,
+    long_press: Option<ButtonOutput>,
+    double_tap: Option<ButtonOutput>,
+}
+
+impl Button {
+    /// `id` identifies the button in the state file, e.g. its pin
+    pub fn new(config: &KeyConfig, id: &str, global: &Config, state_file: Option<&StateFile>) -> Self {
+        let default_channel = global.channel;
+        let mapping = |message: &ButtonMessage, key: String| {
+            let latched = config.restore && state_file.and_then(|s| s.toggle(&key)).unwrap_or(false);
+            Mapping { output: ButtonOutput::new(message, default_channel), latched, key }
+        };
+        let action = config.action.as_ref().map(|action| match action {
+            ButtonAction::Shift { layer, latching } => Action::Shift(Shift {
+                layer: global.layer_index(layer).expect("Validated layer exists"),
+                latching: *latching,
+            }),
+            ButtonAction::NextBank => Action::NextBank,
+            ButtonAction::PrevBank => Action::PrevBank,
+            ButtonAction::SelectBank { bank } => Action::SelectBank(global.bank_index(bank).expect("Validated bank exists")),
+        });
+
+        // Layer and bank names are distinct, so can share the key format
+        let remapped = |messages: &HashMap<String, ButtonMessage>, name: &String| {
+            messages.get(name).map(|message| mapping(message, format!("{id}/{name}")))
+        };
+        let mappings = Mappings::new(
+            action.is_none().then(|| mapping(&config.message, id.to_string())),
+            global.layers.iter().map(|layer| remapped(&config.layers, layer)).collect(),
+            global.banks.iter().map(|bank| remapped(&config.banks, &bank.name)).collect(),
+        );
+
+        let long_press = config.long_press.as_ref().map(|message| ButtonOutput::new(message, default_channel));
+        let double_tap = config.double_tap.as_ref().map(|message| ButtonOutput::new(message, default_channel));
+        let gestures = (long_press.is_some() || double_tap.is_some()).then(|| {
+            GestureDetector::new(
+                long_press.as_ref().map(|_| Duration::from_millis(config.long_press_ms)),
+                double_tap.as_ref().map(|_| Duration::from_millis(config.double_tap_ms)),
+            )
+        });
+
+        Self {
+            restore: config.restore,
+            action,
+            mode: config.mode,
+            mappings,
+            slot: 0,
+            gestures,
+            long_press,
+            double_tap,
+        }
+    }
+
+    /// Messages for the current state of every toggle mapping
+    pub fn latched_messages(&self) -> Vec<[u8; 3]> {
+        match self.mode {
+            ButtonMode::Momentary => Vec::new(),
+            ButtonMode::Toggle => self
+                .mappings
+                .iter()
+                .map(|mapping| if mapping.latched { mapping.output.on() } else { mapping.output.off() })
+                .collect(),
+        }
+    }
+
+    /// Take the state of toggles changed elsewhere, such as by the host, returning whether any changed
+    pub fn feedback(&mut self, incoming: Incoming) -> bool {
+        if self.mode != ButtonMode::Toggle {
+            return false;
+        }
+        let mut changed = false;
+        for mapping in self.mappings.iter_mut() {
+            if let Some(on) = mapping.output.matches(incoming)
+                && on != mapping.latched
+            {
+                mapping.latched = on;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// State file key and latched state of every mapping
+    pub fn toggles(&self) -> impl Iterator<Item = (&str, bool)> {
+        self.mappings.iter().map(|mapping| (mapping.key.as_str(), mapping.latched))
+    }
+
+    /// Handle the button being pressed or released with the `active` layer and bank,
+    /// returning the messages to send
+    pub fn edge(&mut self, pressed: bool, now: Instant, active: Active) -> Vec<[u8; 3]> {
+        let Some(gestures) = self.gestures.as_mut() else {
+            if pressed {
+                self.slot = self.mappings.slot(active);
+            }
+            return self.pressed(pressed).into_iter().collect();
+        };
+
+        let gestures = if pressed { gestures.press(now) } else { gestures.release(now) };
+        // A short press sent by a press is the previous tap's, so goes to its mapping
+        let messages = self.gestures(gestures);
+        if pressed {
+            self.slot = self.mappings.slot(active);
+        }
+        messages
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.gestures.as_ref().and_then(|gestures| gestures.deadline())
+    }
+
+    pub fn expire(&mut self, now: Instant) -> Vec<[u8; 3]> {
+        match self.gestures.as_mut() {
+            Some(gestures) => {
+                let gestures = gestures.expire(now);
+                self.gestures(gestures)
+            }
+            None => Vec::new(),
+        }
+    }
+
+    fn gestures(&mut self, gestures: Vec<(Gesture, bool)>) -> Vec<[u8; 3]> {
+        gestures
+            .into_iter()
+            .filter_map(|(gesture, active)| {
+                let output = match gesture {
+                    Gesture::Short => return self.pressed(active),
+                    Gesture::Long => self.long_press.as_ref()?,
+                    Gesture::DoubleTap => self.double_tap.as_ref()?,
+                };
+                Some(if active { output.on() } else { output.off() })
+            })
+            .collect()
+    }
+
+    // Plain press or release, or a short press when using gestures
+    fn pressed(&mut self, pressed: bool) -> Option<[u8; 3]> {
+        let mode = self.mode;
+        let mapping = self.mappings.get_mut(self.slot)?;
+        match mode {
+            ButtonMode::Momentary => Some(if pressed { mapping.output.on() } else { mapping.output.off() }),
+            ButtonMode::Toggle if pressed => {
+                mapping.latched = !mapping.latched;
+                Some(if mapping.latched { mapping.output.on() } else { mapping.output.off() })
+            }
+            ButtonMode::Toggle => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LONG_PRESS: Duration = Duration::from_millis(500);
+    const DOUBLE_TAP: Duration = Duration::from_millis(300);
+    const SHORT: [(Gesture, bool); 2] = [(Gesture::Short, true), (Gesture::Short, false)];
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn short_press_sent_on_release() {
+        let mut gestures = GestureDetector::new(Some(LONG_PRESS), None);
+        let start = Instant::now();
+        assert_eq!(gestures.press(start), []);
+        assert_eq!(gestures.expire(start + ms(100)), []);
+        assert_eq!(gestures.release(start + ms(100)), SHORT);
+        assert_eq!(gestures.deadline(), None);
+    }
+
+    #[test]
+    fn short_press_waits_out_double_tap() {
+        let mut gestures = GestureDetector::new(None, Some(DOUBLE_TAP));
+        let start = Instant::now();
+        assert_eq!(gestures.press(start), []);
+        assert_eq!(gestures.release(start + ms(100)), []);
+        assert_eq!(gestures.deadline(), Some(start + ms(100) + DOUBLE_TAP));
+        assert_eq!(gestures.expire(start + ms(399)), []);
+        assert_eq!(gestures.expire(start + ms(400)), SHORT);
+        assert_eq!(gestures.deadline(), None);
+    }
+
+    #[test]
+    fn long_press_starts_at_deadline_and_ends_on_release() {
+        let mut gestures = GestureDetector::new(Some(LONG_PRESS), Some(DOUBLE_TAP));
+        let start = Instant::now();
+        assert_eq!(gestures.press(start), []);
+        assert_eq!(gestures.deadline(), Some(start + LONG_PRESS));
+        assert_eq!(gestures.expire(start + ms(499)), []);
+        assert_eq!(gestures.expire(start + LONG_PRESS), [(Gesture::Long, true)]);
+        assert_eq!(gestures.deadline(), None);
+        assert_eq!(gestures.release(start + ms(800)), [(Gesture::Long, false)]);
+        // No double tap window after a long press
+        assert_eq!(gestures.deadline(), None);
+    }
+
+    #[test]
+    fn double_tap_starts_on_second_press_and_ends_on_release() {
+        let mut gestures = GestureDetector::new(Some(LONG_PRESS), Some(DOUBLE_TAP));
+        let start = Instant::now();
+        assert_eq!(gestures.press(start), []);
+        assert_eq!(gestures.release(start + ms(100)), []);
+        assert_eq!(gestures.press(start + ms(300)), [(Gesture::DoubleTap, true)]);
+        // Held past the long press threshold, but already a double tap
+        assert_eq!(gestures.expire(start + ms(1000)), []);
+        assert_eq!(gestures.release(start + ms(1000)), [(Gesture::DoubleTap, false)]);
+        assert_eq!(gestures.deadline(), None);
+    }
+
+    #[test]
+    fn second_tap_after_window_is_new_press() {
+        let mut gestures = GestureDetector::new(None, Some(DOUBLE_TAP));
+        let start = Instant::now();
+        assert_eq!(gestures.press(start), []);
+        assert_eq!(gestures.release(start + ms(100)), []);
+        // Pressed again before the expired short press was sent
+        assert_eq!(gestures.press(start + ms(600)), SHORT);
+        assert_eq!(gestures.release(start + ms(700)), []);
+        assert_eq!(gestures.expire(start + ms(1000)), SHORT);
+    }
+}
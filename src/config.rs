@@ -0,0 +1,1287 @@
+use crate::backend::Pull;
+use anyhow::{Result, bail};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How a note is released
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NoteRelease {
+    /// Send a Note Off message
+    #[default]
+    NoteOff,
+    /// Send a Note On message with velocity 0
+    ZeroVelocity,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ButtonMode {
+    /// Press sends the on message, release sends the off message
+    #[default]
+    Momentary,
+    /// Each press flips between the on and off messages, release is ignored
+    Toggle,
+}
+
+/// Something a button does instead of sending MIDI
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ButtonAction {
+    /// Activate `layer` while held, or until pressed again when `latching`
+    Shift {
+        layer: String,
+        #[serde(default)]
+        latching: bool,
+    },
+    /// Select the next bank, wrapping around after the last
+    NextBank,
+    /// Select the previous bank, wrapping around before the first
+    PrevBank,
+    /// Select `bank`
+    SelectBank { bank: String },
+}
+
+impl ButtonAction {
+    /// Layer the action refers to
+    pub fn layer(&self) -> Option<&String> {
+        match self {
+            ButtonAction::Shift { layer, .. } => Some(layer),
+            _ => None,
+        }
+    }
+
+    /// Bank the action refers to
+    pub fn bank(&self) -> Option<&String> {
+        match self {
+            ButtonAction::SelectBank { bank } => Some(bank),
+            _ => None,
+        }
+    }
+}
+
+/// MIDI message sent by a button, either a CC or a note
+#[derive(Debug, Clone, Deserialize)]
+pub struct ButtonMessage {
+    #[serde(default)]
+    pub cc: Option<u8>,
+    #[serde(default)]
+    pub note: Option<u8>,
+    /// CC value sent when the button turns on
+    #[serde(default = "default_on_value")]
+    pub on_value: u8,
+    /// CC value sent when the button turns off
+    #[serde(default)]
+    pub off_value: u8,
+    /// Note On velocity
+    #[serde(default = "default_velocity")]
+    pub velocity: u8,
+    #[serde(default)]
+    pub note_release: NoteRelease,
+    /// MIDI channel 1-16, defaults to [`Config::channel`]
+    #[serde(default)]
+    pub channel: Option<u8>,
+}
+
+impl ButtonMessage {
+    pub fn validate(&self) -> Result<()> {
+        match (self.cc, self.note) {
+            (Some(cc), None) => validate_data_byte("cc", cc)?,
+            (None, Some(note)) => validate_data_byte("note", note)?,
+            (Some(_), Some(_)) => bail!("Button message can't have both `cc` and `note`"),
+            (None, None) => bail!("Button message needs a `cc` or `note`"),
+        }
+        validate_data_byte("on_value", self.on_value)?;
+        validate_data_byte("off_value", self.off_value)?;
+        validate_data_byte("velocity", self.velocity)?;
+        if let Some(channel) = self.channel {
+            validate_channel(channel)?;
+        }
+        Ok(())
+    }
+}
+
+/// How relative encoders encode a signed delta in a CC value
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelativeEncoding {
+    /// 1..=63 up, 127..=65 down
+    #[default]
+    TwosComplement,
+    /// 64 plus the delta, 65..=127 up, 63..=1 down
+    BinaryOffset,
+    /// Magnitude with 0x40 set when turning down, 1..=63 up, 65..=127 down
+    SignedBit,
+}
+
+/// An input pin, either a native GPIO or a pin on an expander
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PinId {
+    /// BCM GPIO number
+    Gpio(u8),
+    /// Pin 0-15 on the expander called `chip`, where 0-7 are GPA0-7 and 8-15 are GPB0-7
+    Expander { chip: String, pin: u8 },
+}
+
+impl FromStr for PinId {
+    type Err = anyhow::Error;
+
+    /// `17`, or `chip:pin` with the pin as `0`-`15` or `A0`-`B7`
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((chip, pin)) = s.split_once(':') else {
+            return Ok(PinId::Gpio(s.parse().map_err(|_| anyhow::anyhow!("Invalid pin `{s}`"))?));
+        };
+        let pin = match pin.as_bytes() {
+            [port @ (b'a' | b'A' | b'b' | b'B'), bit @ b'0'..=b'7'] => {
+                (if port.eq_ignore_ascii_case(&b'a') { 0 } else { 8 }) + bit - b'0'
+            }
+            _ => pin.parse().map_err(|_| anyhow::anyhow!("Invalid expander pin `{s}`"))?,
+        };
+        Ok(PinId::Expander { chip: chip.to_string(), pin })
+    }
+}
+
+impl fmt::Display for PinId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PinId::Gpio(pin) => write!(f, "{pin}"),
+            PinId::Expander { chip, pin } => write!(f, "{chip}:{pin}"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PinId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Gpio(u8),
+            Name(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Gpio(pin) => Ok(PinId::Gpio(pin)),
+            Raw::Name(name) => name.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// What a button or matrix key does when pressed, apart from how it's wired
+#[derive(Debug, Clone, Deserialize)]
+pub struct KeyConfig {
+    #[serde(flatten)]
+    pub message: ButtonMessage,
+    #[serde(default)]
+    pub mode: ButtonMode,
+    /// Restore the latched state of a toggle from [`Config::state_file`] on startup
+    #[serde(default)]
+    pub restore: bool,
+    /// Message for holding the button past `long_press_ms`
+    #[serde(default)]
+    pub long_press: Option<ButtonMessage>,
+    #[serde(default = "default_long_press_ms")]
+    pub long_press_ms: u64,
+    /// Message for pressing the button twice within `double_tap_ms`
+    #[serde(default)]
+    pub double_tap: Option<ButtonMessage>,
+    #[serde(default = "default_double_tap_ms")]
+    pub double_tap_ms: u64,
+    /// Replaces the button's message
+    #[serde(default)]
+    pub action: Option<ButtonAction>,
+    /// Messages used instead of the button's own message while a layer is active
+    #[serde(default)]
+    pub layers: HashMap<String, ButtonMessage>,
+    /// Messages used instead of the button's own message while a bank is active
+    #[serde(default)]
+    pub banks: HashMap<String, ButtonMessage>,
+    /// Names of the outputs to send to, defaults to all of them
+    #[serde(default)]
+    pub outputs: Vec<String>,
+}
+
+impl KeyConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.action.is_none() {
+            self.message.validate()?;
+        }
+        for message in [&self.long_press, &self.double_tap].into_iter().flatten().chain(self.layers.values()).chain(self.banks.values()) {
+            message.validate()?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ButtonConfig {
+    pub pin: PinId,
+    #[serde(default)]
+    pub pull: Option<Pull>,
+    /// Shorthand for `pull = "down"`, kept for older configs
+    #[serde(default)]
+    pub pull_down: bool,
+    /// Whether the input is low while pressed, defaults to true unless pulled down
+    #[serde(default)]
+    pub active_low: Option<bool>,
+    #[serde(default)]
+    pub debounce_ms: Option<u64>,
+    #[serde(flatten)]
+    pub key: KeyConfig,
+}
+
+impl ButtonConfig {
+    pub fn validate(&self) -> Result<()> {
+        self.key.validate()
+    }
+
+    pub fn pull(&self) -> Pull {
+        self.pull.unwrap_or(if self.pull_down { Pull::Down } else { Pull::Up })
+    }
+
+    pub fn active_low(&self) -> bool {
+        self.active_low.unwrap_or(self.pull() != Pull::Down)
+    }
+}
+
+/// A key of a [`ButtonMatrixConfig`]
+#[derive(Debug, Clone, Deserialize)]
+pub struct MatrixKeyConfig {
+    /// Index in [`ButtonMatrixConfig::rows`]
+    pub row: u8,
+    /// Index in [`ButtonMatrixConfig::columns`]
+    pub column: u8,
+    #[serde(flatten)]
+    pub key: KeyConfig,
+}
+
+/// A grid of keys read by driving one row at a time and reading the columns
+#[derive(Debug, Clone, Deserialize)]
+pub struct ButtonMatrixConfig {
+    /// GPIO pins driven while scanning, left floating when not selected
+    pub rows: Vec<u8>,
+    /// GPIO pins read for each row
+    pub columns: Vec<u8>,
+    /// Pull resistor on the columns
+    #[serde(default)]
+    pub pull: Pull,
+    /// Drive the selected row low, defaults to true unless the columns are pulled down
+    #[serde(default)]
+    pub active_low: Option<bool>,
+    /// Full scans per second
+    #[serde(default = "default_scan_rate")]
+    pub scan_rate: f64,
+    /// Changes of a key within this long of its last are ignored
+    #[serde(default = "default_matrix_debounce_ms")]
+    pub debounce_ms: u64,
+    /// The matrix has a diode per key, so can't ghost and any combination of keys is read
+    #[serde(default)]
+    pub diodes: bool,
+    pub keys: Vec<MatrixKeyConfig>,
+}
+
+impl ButtonMatrixConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.rows.is_empty() || self.columns.is_empty() {
+            bail!("Button matrix needs at least one row and one column");
+        }
+        if self.scan_rate <= 0.0 {
+            bail!("Button matrix needs a positive `scan_rate`");
+        }
+        for (index, key) in self.keys.iter().enumerate() {
+            if key.row as usize >= self.rows.len() || key.column as usize >= self.columns.len() {
+                bail!("Button matrix has no key at row {}, column {}", key.row, key.column);
+            }
+            if self.keys[..index].iter().any(|other| (other.row, other.column) == (key.row, key.column)) {
+                bail!("Button matrix key at row {}, column {} is declared more than once", key.row, key.column);
+            }
+            key.key.validate()?;
+        }
+        Ok(())
+    }
+
+    pub fn active_low(&self) -> bool {
+        self.active_low.unwrap_or(self.pull != Pull::Down)
+    }
+}
+
+/// Encoder or potentiometer mapping used while a layer or bank is active
+#[derive(Debug, Clone, Deserialize)]
+pub struct EncoderMapping {
+    pub cc: u8,
+    /// MIDI channel 1-16, defaults to the encoder's channel
+    #[serde(default)]
+    pub channel: Option<u8>,
+}
+
+/// How a ring of LEDs shows an encoder's value
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RingStyle {
+    /// Light every LED up to the value
+    #[default]
+    Bar,
+    /// Light the one LED nearest the value
+    Dot,
+    /// Light outwards from the middle as the value rises
+    Spread,
+}
+
+/// LEDs around an encoder showing its value
+#[derive(Debug, Clone, Deserialize)]
+pub struct RingConfig {
+    /// GPIO pins in order from the lowest value to the highest
+    pub pins: Vec<u8>,
+    #[serde(default)]
+    pub style: RingStyle,
+    /// Drive the pins low while lit
+    #[serde(default)]
+    pub active_low: bool,
+}
+
+/// How encoder pins are read
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Decoding {
+    /// Edge interrupts on both channels
+    #[default]
+    Interrupt,
+    /// Read both channels at the engine's polling rate
+    Polling,
+}
+
+/// What turning an encoder does while its switch is held
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum PushTurn {
+    /// Send to another CC, with its own absolute value
+    AlternateCc {
+        cc: u8,
+        /// MIDI channel 1-16, defaults to the encoder's channel
+        #[serde(default)]
+        channel: Option<u8>,
+    },
+    /// Move by `step` per detent instead of the encoder's `step`, for fine/coarse adjustment
+    Step { step: u8 },
+}
+
+/// Speeds up fast turns by moving more than one step per detent
+#[derive(Debug, Clone, Deserialize)]
+pub struct Acceleration {
+    /// Detents at least this far apart move by one step
+    #[serde(default = "default_slow_ms")]
+    pub slow_ms: u64,
+    /// Detents at most this far apart move by `max_step`
+    #[serde(default = "default_fast_ms")]
+    pub fast_ms: u64,
+    /// Largest step per detent
+    #[serde(default = "default_max_step")]
+    pub max_step: u8,
+    /// Shape of the curve between `slow_ms` and `fast_ms`, 1.0 is linear and higher values
+    /// keep small steps for longer
+    #[serde(default = "default_exponent")]
+    pub exponent: f64,
+}
+
+impl Acceleration {
+    /// Step size for a detent `interval` after the previous one
+    pub fn step(&self, interval: Duration) -> u8 {
+        let (slow, fast) = (self.slow_ms as f64, self.fast_ms as f64);
+        let ms = interval.as_secs_f64() * 1000.0;
+        let speed = ((slow - ms) / (slow - fast)).clamp(0.0, 1.0);
+        1 + ((self.max_step - 1) as f64 * speed.powf(self.exponent)).round() as u8
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.fast_ms >= self.slow_ms {
+            bail!("Acceleration `fast_ms` ({}) must be less than `slow_ms` ({})", self.fast_ms, self.slow_ms);
+        }
+        if !(1..=127).contains(&self.max_step) {
+            bail!("Acceleration `max_step` must be between 1 and 127, got {}", self.max_step);
+        }
+        if self.exponent <= 0.0 {
+            bail!("Acceleration `exponent` must be positive, got {}", self.exponent);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RotaryEncoderConfig {
+    pub pin_a: PinId,
+    pub pin_b: PinId,
+    pub cc: u8,
+    #[serde(default)]
+    pub relative_value: bool,
+    #[serde(default)]
+    pub relative_encoding: RelativeEncoding,
+    /// MIDI channel 1-16, defaults to [`Config::channel`]
+    #[serde(default)]
+    pub channel: Option<u8>,
+    /// Lowest absolute value
+    #[serde(default)]
+    pub min: u8,
+    /// Highest absolute value
+    #[serde(default = "default_max")]
+    pub max: u8,
+    /// Absolute value on startup, defaults to 64 clamped to `min`..=`max`
+    #[serde(default)]
+    pub initial_value: Option<u8>,
+    /// Wrap around past `min` and `max` instead of stopping
+    #[serde(default)]
+    pub wrap: bool,
+    #[serde(default)]
+    pub acceleration: Option<Acceleration>,
+    /// Gray code transitions per detent: 4 for full-step, 2 for half-step and 1 for quarter-step encoders
+    #[serde(default = "default_steps_per_detent")]
+    pub steps_per_detent: u8,
+    /// Swap the direction without rewiring `pin_a` and `pin_b`
+    #[serde(default)]
+    pub reverse: bool,
+    #[serde(default)]
+    pub decoding: Decoding,
+    /// Pull resistor on `pin_a` and `pin_b`
+    #[serde(default)]
+    pub pull: Pull,
+    /// Invert `pin_a` and `pin_b`
+    #[serde(default)]
+    pub active_low: bool,
+    /// Steps moved per detent
+    #[serde(default = "default_step")]
+    pub step: u8,
+    /// GPIO pin of an integrated push switch
+    #[serde(default)]
+    pub pin_switch: Option<PinId>,
+    #[serde(default)]
+    pub switch_pull: Pull,
+    /// Whether the switch is low while pressed, defaults to true unless pulled down
+    #[serde(default)]
+    pub switch_active_low: Option<bool>,
+    /// Message sent by the push switch
+    #[serde(default)]
+    pub switch: Option<ButtonMessage>,
+    #[serde(default)]
+    pub push_turn: Option<PushTurn>,
+    #[serde(default)]
+    pub layers: HashMap<String, EncoderMapping>,
+    #[serde(default)]
+    pub banks: HashMap<String, EncoderMapping>,
+    /// Names of the outputs to send to, defaults to all of them
+    #[serde(default)]
+    pub outputs: Vec<String>,
+    #[serde(default)]
+    pub ring: Option<RingConfig>,
+}
+
+impl RotaryEncoderConfig {
+    pub fn switch_active_low(&self) -> bool {
+        self.switch_active_low.unwrap_or(self.switch_pull != Pull::Down)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if let Some(channel) = self.channel {
+            validate_channel(channel)?;
+        }
+        validate_data_byte("cc", self.cc)?;
+        validate_data_byte("max", self.max)?;
+        let (min, max) = (self.min, self.max);
+        if min > max {
+            bail!("Rotary encoder `min` ({min}) is greater than `max` ({max})");
+        }
+        if let Some(initial_value) = self.initial_value
+            && !(min..=max).contains(&initial_value)
+        {
+            bail!("Rotary encoder `initial_value` ({initial_value}) is outside {min}..={max}");
+        }
+        if let Some(acceleration) = &self.acceleration {
+            acceleration.validate()?;
+        }
+        if ![1, 2, 4].contains(&self.steps_per_detent) {
+            bail!("Rotary encoder `steps_per_detent` must be 1, 2 or 4, got {}", self.steps_per_detent);
+        }
+        validate_step(self.step)?;
+        if self.pin_switch.is_none() && (self.switch.is_some() || self.push_turn.is_some()) {
+            bail!("Rotary encoder `switch` and `push_turn` need a `pin_switch`");
+        }
+        if let Some(switch) = &self.switch {
+            switch.validate()?;
+        }
+        match self.push_turn {
+            Some(PushTurn::AlternateCc { cc, channel }) => {
+                validate_data_byte("cc", cc)?;
+                if let Some(channel) = channel {
+                    validate_channel(channel)?;
+                }
+            }
+            Some(PushTurn::Step { step }) => validate_step(step)?,
+            None => {}
+        }
+        if let Some(ring) = &self.ring {
+            if ring.pins.is_empty() {
+                bail!("Rotary encoder `ring` needs at least one pin");
+            }
+            if self.relative_value {
+                bail!("Rotary encoder `ring` needs an absolute value, not `relative_value`");
+            }
+        }
+        for mapping in self.layers.values().chain(self.banks.values()) {
+            validate_data_byte("cc", mapping.cc)?;
+            if let Some(channel) = mapping.channel {
+                validate_channel(channel)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// SPI bus and chip select an ADC or expander is wired to
+#[derive(Debug, Clone, Deserialize)]
+pub struct SpiConfig {
+    /// SPI bus, 0 for `/dev/spidev0.*`
+    #[serde(default)]
+    pub bus: u8,
+    /// Chip select, 0 for CE0
+    #[serde(default)]
+    pub slave_select: u8,
+    #[serde(default = "default_spi_clock_hz")]
+    pub clock_hz: u32,
+}
+
+/// An MCP23S17 on an SPI bus
+#[derive(Debug, Clone, Deserialize)]
+pub struct Mcp23s17Config {
+    #[serde(flatten)]
+    pub spi: SpiConfig,
+    /// Hardware address 0-7 set by A0-A2, for chips sharing a chip select
+    #[serde(default)]
+    pub address: u8,
+}
+
+/// Model of a GPIO expander
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ExpanderKind {
+    /// 16 pin I2C expander
+    Mcp23017 {
+        /// I2C bus, 1 for `/dev/i2c-1`
+        #[serde(default = "default_i2c_bus")]
+        bus: u8,
+        /// 0x20 to 0x27, set by A0-A2
+        #[serde(default = "default_mcp23017_address")]
+        address: u16,
+    },
+    /// 16 pin SPI expander
+    Mcp23s17(Mcp23s17Config),
+}
+
+/// A GPIO expander buttons and encoders can use the pins of
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExpanderConfig {
+    /// Used as `name:pin` in control pins
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: ExpanderKind,
+    /// Native GPIO pin the expander's INTA or INTB output is connected to
+    pub interrupt: u8,
+}
+
+impl ExpanderConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.name.is_empty() || self.name.contains(':') {
+            bail!("Expander name `{}` must not be empty or contain `:`", self.name);
+        }
+        match &self.kind {
+            ExpanderKind::Mcp23017 { address, .. } if !(0x20..=0x27).contains(address) => {
+                bail!("Expander `{}` `address` must be between 0x20 and 0x27, got {address:#x}", self.name)
+            }
+            ExpanderKind::Mcp23s17(mcp23s17) if mcp23s17.address > 7 => {
+                bail!("Expander `{}` `address` must be between 0 and 7, got {}", self.name, mcp23s17.address)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Gains the ADS1115 supports, with the input voltage each reads as full scale
+const ADS1115_GAINS: [(f64, f64); 6] = [(2.0 / 3.0, 6.144), (1.0, 4.096), (2.0, 2.048), (4.0, 1.024), (8.0, 0.512), (16.0, 0.256)];
+/// Data rates the ADS1115 supports, in samples per second
+const ADS1115_DATA_RATES: [u16; 8] = [8, 16, 32, 64, 128, 250, 475, 860];
+
+/// An ADS1115 on an I2C bus
+#[derive(Debug, Clone, Deserialize)]
+pub struct Ads1115Config {
+    /// I2C bus, 1 for `/dev/i2c-1`
+    #[serde(default = "default_i2c_bus")]
+    pub bus: u8,
+    /// 0x48 to 0x4B, depending on what ADDR is tied to
+    #[serde(default = "default_ads1115_address")]
+    pub address: u16,
+    /// Programmable gain, 2/3, 1, 2, 4, 8 or 16
+    #[serde(default = "default_ads1115_gain")]
+    pub gain: f64,
+    /// Conversions per second, 8, 16, 32, 64, 128, 250, 475 or 860
+    #[serde(default = "default_ads1115_data_rate")]
+    pub data_rate: u16,
+    /// Voltage across the potentiometers, the most they can read
+    #[serde(default = "default_supply_voltage")]
+    pub supply_voltage: f64,
+}
+
+impl Ads1115Config {
+    /// Index of `gain` in the PGA setting, 2/3 can be written as 0.667
+    pub fn gain_index(&self) -> Option<usize> {
+        ADS1115_GAINS.iter().position(|(gain, _)| (gain - self.gain).abs() < 0.001)
+    }
+
+    pub fn data_rate_index(&self) -> Option<usize> {
+        ADS1115_DATA_RATES.iter().position(|&rate| rate == self.data_rate)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if !(0x48..=0x4B).contains(&self.address) {
+            bail!("ADS1115 `address` must be between 0x48 and 0x4B, got {:#x}", self.address);
+        }
+        if self.gain_index().is_none() {
+            bail!("ADS1115 `gain` must be 2/3, 1, 2, 4, 8 or 16, got {}", self.gain);
+        }
+        if self.data_rate_index().is_none() {
+            bail!("ADS1115 `data_rate` must be one of {ADS1115_DATA_RATES:?}, got {}", self.data_rate);
+        }
+        if self.supply_voltage <= 0.0 {
+            bail!("ADS1115 needs a positive `supply_voltage`");
+        }
+        Ok(())
+    }
+}
+
+/// Model of an analog-to-digital converter
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AdcKind {
+    /// 8 channel, 10 bit SPI ADC
+    Mcp3008(SpiConfig),
+    /// 8 channel, 12 bit SPI ADC
+    Mcp3208(SpiConfig),
+    /// 4 channel, 16 bit I2C ADC, of which single-ended readings use 15
+    Ads1115(Ads1115Config),
+}
+
+impl AdcKind {
+    /// Number of input channels
+    pub fn inputs(&self) -> u8 {
+        match self {
+            AdcKind::Mcp3008(_) | AdcKind::Mcp3208(_) => 8,
+            AdcKind::Ads1115(_) => 4,
+        }
+    }
+
+    /// Highest raw reading
+    pub fn full_scale(&self) -> u16 {
+        match self {
+            AdcKind::Mcp3008(_) => 1023,
+            AdcKind::Mcp3208(_) => 4095,
+            // The gain's range is usually wider than the supply, which is all a pot can reach
+            AdcKind::Ads1115(ads1115) => {
+                let range = ADS1115_GAINS[ads1115.gain_index().unwrap_or(1)].1;
+                (ads1115.supply_voltage / range * 32767.0).round().min(32767.0) as u16
+            }
+        }
+    }
+}
+
+/// An ADC potentiometers are read from
+#[derive(Debug, Clone, Deserialize)]
+pub struct AdcConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: AdcKind,
+    /// Readings per second of each potentiometer on the ADC
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PotentiometerConfig {
+    /// Name of the ADC in [`Config::adcs`]
+    pub adc: String,
+    /// ADC input channel the wiper is connected to
+    pub input: u8,
+    pub cc: u8,
+    /// MIDI channel 1-16, defaults to [`Config::channel`]
+    #[serde(default)]
+    pub channel: Option<u8>,
+    /// Raw reading for a CC value of 0, defaults to 0
+    #[serde(default)]
+    pub raw_min: Option<u16>,
+    /// Raw reading for a CC value of 127, defaults to the ADC's full scale
+    #[serde(default)]
+    pub raw_max: Option<u16>,
+    /// Weight of the previous reading when averaging, from 0.0 for none up to but not including 1.0
+    #[serde(default = "default_smoothing")]
+    pub smoothing: f64,
+    /// How far past the edge of the current CC value, in CC steps, the reading must move to change it
+    #[serde(default = "default_deadband")]
+    pub deadband: f64,
+    #[serde(default)]
+    pub layers: HashMap<String, EncoderMapping>,
+    #[serde(default)]
+    pub banks: HashMap<String, EncoderMapping>,
+    /// Names of the outputs to send to, defaults to all of them
+    #[serde(default)]
+    pub outputs: Vec<String>,
+}
+
+impl PotentiometerConfig {
+    pub fn validate(&self) -> Result<()> {
+        validate_data_byte("cc", self.cc)?;
+        if let Some(channel) = self.channel {
+            validate_channel(channel)?;
+        }
+        if !(0.0..1.0).contains(&self.smoothing) {
+            bail!("Potentiometer `smoothing` must be at least 0.0 and less than 1.0, got {}", self.smoothing);
+        }
+        if self.deadband < 0.0 {
+            bail!("Potentiometer `deadband` can't be negative, got {}", self.deadband);
+        }
+        for mapping in self.layers.values().chain(self.banks.values()) {
+            validate_data_byte("cc", mapping.cc)?;
+            if let Some(channel) = mapping.channel {
+                validate_channel(channel)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A GPIO output, such as an LED, driven by incoming MIDI
+#[derive(Debug, Clone, Deserialize)]
+pub struct LedConfig {
+    pub pin: u8,
+    #[serde(default)]
+    pub cc: Option<u8>,
+    #[serde(default)]
+    pub note: Option<u8>,
+    /// MIDI channel 1-16, defaults to [`Config::channel`]
+    #[serde(default)]
+    pub channel: Option<u8>,
+    /// Lowest CC value or note velocity that turns the output on
+    #[serde(default = "default_threshold")]
+    pub threshold: u8,
+    /// Drive the pin low while on
+    #[serde(default)]
+    pub active_low: bool,
+    /// Set the brightness from the CC value or note velocity instead of switching at `threshold`
+    #[serde(default)]
+    pub pwm: bool,
+    #[serde(default = "default_pwm_frequency")]
+    pub pwm_frequency: f64,
+    /// Hardware PWM channel routed to `pin`, otherwise PWM is done in software
+    #[serde(default)]
+    pub pwm_channel: Option<u8>,
+}
+
+impl LedConfig {
+    pub fn validate(&self) -> Result<()> {
+        match (self.cc, self.note) {
+            (Some(cc), None) => validate_data_byte("cc", cc)?,
+            (None, Some(note)) => validate_data_byte("note", note)?,
+            (Some(_), Some(_)) => bail!("LED on pin {} can't have both `cc` and `note`", self.pin),
+            (None, None) => bail!("LED on pin {} needs a `cc` or `note`", self.pin),
+        }
+        validate_data_byte("threshold", self.threshold)?;
+        if let Some(channel) = self.channel {
+            validate_channel(channel)?;
+        }
+        if self.pwm && self.pwm_frequency <= 0.0 {
+            bail!("LED on pin {} needs a positive `pwm_frequency`", self.pin);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type")]
+pub enum ControlConfig {
+    Button(ButtonConfig),
+    RotaryEncoder(RotaryEncoderConfig),
+    Potentiometer(PotentiometerConfig),
+    ButtonMatrix(ButtonMatrixConfig),
+}
+
+impl ControlConfig {
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            ControlConfig::Button(button) => button.validate(),
+            ControlConfig::RotaryEncoder(encoder) => encoder.validate(),
+            ControlConfig::Potentiometer(potentiometer) => potentiometer.validate(),
+            ControlConfig::ButtonMatrix(matrix) => matrix.validate(),
+        }
+    }
+
+    /// Names of the outputs the control sends to, empty for all of them. One list per
+    /// key for a matrix, whose keys send on their own.
+    pub fn outputs(&self) -> Vec<&[String]> {
+        match self {
+            ControlConfig::Button(button) => vec![&button.key.outputs],
+            ControlConfig::RotaryEncoder(encoder) => vec![&encoder.outputs],
+            ControlConfig::Potentiometer(potentiometer) => vec![&potentiometer.outputs],
+            ControlConfig::ButtonMatrix(matrix) => matrix.keys.iter().map(|key| key.key.outputs.as_slice()).collect(),
+        }
+    }
+
+    /// Button-like keys of the control, with a description for errors
+    pub fn keys(&self) -> Vec<(String, &KeyConfig)> {
+        match self {
+            ControlConfig::Button(button) => vec![(format!("Button on pin {}", button.pin), &button.key)],
+            ControlConfig::ButtonMatrix(matrix) => matrix
+                .keys
+                .iter()
+                .map(|key| (format!("Button matrix key at row {}, column {}", key.row, key.column), &key.key))
+                .collect(),
+            ControlConfig::RotaryEncoder(_) | ControlConfig::Potentiometer(_) => Vec::new(),
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_spi_clock_hz() -> u32 {
+    1_000_000
+}
+
+fn default_i2c_bus() -> u8 {
+    1
+}
+
+fn default_mcp23017_address() -> u16 {
+    0x20
+}
+
+fn default_ads1115_address() -> u16 {
+    0x48
+}
+
+fn default_ads1115_gain() -> f64 {
+    1.0
+}
+
+fn default_ads1115_data_rate() -> u16 {
+    860
+}
+
+fn default_supply_voltage() -> f64 {
+    3.3
+}
+
+fn default_scan_rate() -> f64 {
+    1000.0
+}
+
+fn default_matrix_debounce_ms() -> u64 {
+    5
+}
+
+fn default_sample_rate() -> f64 {
+    200.0
+}
+
+fn default_smoothing() -> f64 {
+    0.5
+}
+
+fn default_deadband() -> f64 {
+    0.5
+}
+
+fn default_pwm_frequency() -> f64 {
+    500.0
+}
+
+fn default_threshold() -> u8 {
+    64
+}
+
+fn default_baud_rate() -> u32 {
+    38400
+}
+
+fn default_long_press_ms() -> u64 {
+    500
+}
+
+fn default_double_tap_ms() -> u64 {
+    300
+}
+
+fn default_channel() -> u8 {
+    1
+}
+
+fn default_slow_ms() -> u64 {
+    100
+}
+
+fn default_fast_ms() -> u64 {
+    10
+}
+
+fn default_max_step() -> u8 {
+    8
+}
+
+fn default_exponent() -> f64 {
+    1.0
+}
+
+fn default_steps_per_detent() -> u8 {
+    4
+}
+
+fn default_step() -> u8 {
+    1
+}
+
+fn default_max() -> u8 {
+    127
+}
+
+fn default_on_value() -> u8 {
+    127
+}
+
+fn default_velocity() -> u8 {
+    127
+}
+
+fn validate_channel(channel: u8) -> Result<()> {
+    if !(1..=16).contains(&channel) {
+        bail!("MIDI channel must be between 1 and 16, got {channel}");
+    }
+    Ok(())
+}
+
+fn validate_step(step: u8) -> Result<()> {
+    if !(1..=127).contains(&step) {
+        bail!("Rotary encoder `step` must be between 1 and 127, got {step}");
+    }
+    Ok(())
+}
+
+fn validate_data_byte(name: &str, value: u8) -> Result<()> {
+    if value > 127 {
+        bail!("`{name}` must be between 0 and 127, got {value}");
+    }
+    Ok(())
+}
+
+/// Kind of MIDI output
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OutputKind {
+    /// A virtual port, named `port` or the output's name
+    Virtual {
+        #[serde(default)]
+        port: Option<String>,
+    },
+    /// An existing port whose name contains `connect`, or matches it as a regex
+    Port { connect: String },
+    /// A UART driving a DIN MIDI socket
+    Serial {
+        path: PathBuf,
+        /// 38400 runs at MIDI's 31250 baud with the `midi-uart0` overlay
+        #[serde(default = "default_baud_rate")]
+        baud_rate: u32,
+        /// Leave out repeated status bytes
+        #[serde(default = "default_true")]
+        running_status: bool,
+    },
+}
+
+/// A named MIDI output controls can be routed to
+#[derive(Debug, Clone, Deserialize)]
+pub struct OutputConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: OutputKind,
+}
+
+/// A named set of mappings, selected by buttons or Program Change
+#[derive(Debug, Clone, Deserialize)]
+pub struct BankConfig {
+    pub name: String,
+    /// Program Change number that selects the bank, defaults to its position in `banks`
+    #[serde(default)]
+    pub program: Option<u8>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    /// Default MIDI channel 1-16 for controls without their own
+    #[serde(default = "default_channel")]
+    pub channel: u8,
+    /// File the latched state of toggle buttons is saved to
+    #[serde(default)]
+    pub state_file: Option<PathBuf>,
+    /// Names of the layers controls can be remapped in
+    #[serde(default)]
+    pub layers: Vec<String>,
+    /// Banks controls can be remapped in, the first is active on startup
+    #[serde(default)]
+    pub banks: Vec<BankConfig>,
+    /// MIDI outputs, replacing the command line port when given
+    #[serde(default)]
+    pub outputs: Vec<OutputConfig>,
+    pub controls: Vec<ControlConfig>,
+    /// Outputs driven by incoming MIDI
+    #[serde(default)]
+    pub leds: Vec<LedConfig>,
+    /// ADCs potentiometers are read from
+    #[serde(default)]
+    pub adcs: Vec<AdcConfig>,
+    /// GPIO expanders adding pins for buttons and encoders
+    #[serde(default)]
+    pub expanders: Vec<ExpanderConfig>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    /// Index of the layer called `name`
+    pub fn layer_index(&self, name: &str) -> Option<usize> {
+        self.layers.iter().position(|layer| layer == name)
+    }
+
+    /// Index of the ADC called `name`
+    pub fn adc_index(&self, name: &str) -> Option<usize> {
+        self.adcs.iter().position(|adc| adc.name == name)
+    }
+
+    /// Index of the expander called `name`
+    pub fn expander_index(&self, name: &str) -> Option<usize> {
+        self.expanders.iter().position(|expander| expander.name == name)
+    }
+
+    /// Check that an expander pin is on a declared expander and can be pulled `pull`
+    fn validate_pin(&self, pin: &PinId, pull: Pull) -> Result<()> {
+        let PinId::Expander { chip, pin: number } = pin else {
+            return Ok(());
+        };
+        if self.expander_index(chip).is_none() {
+            bail!("Expander `{chip}` is not declared in `expanders`");
+        }
+        if *number > 15 {
+            bail!("Expander pin must be between 0 and 15, got `{pin}`");
+        }
+        if pull == Pull::Down {
+            bail!("Expander pin `{pin}` can't be pulled down, only `pull = \"up\"` or `\"off\"`");
+        }
+        Ok(())
+    }
+
+    /// Index of the output called `name`
+    pub fn output_index(&self, name: &str) -> Option<usize> {
+        self.outputs.iter().position(|output| output.name == name)
+    }
+
+    /// Index of the bank called `name`
+    pub fn bank_index(&self, name: &str) -> Option<usize> {
+        self.banks.iter().position(|bank| bank.name == name)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        validate_channel(self.channel)?;
+        for (index, bank) in self.banks.iter().enumerate() {
+            if self.bank_index(&bank.name) != Some(index) {
+                bail!("Bank `{}` is declared more than once", bank.name);
+            }
+            if self.layer_index(&bank.name).is_some() {
+                bail!("`{}` is both a layer and a bank", bank.name);
+            }
+            if let Some(program) = bank.program {
+                validate_data_byte("program", program)?;
+            }
+            // Only the first bank with a program number could be selected by it
+            let program = bank.program.unwrap_or(index as u8);
+            let mut earlier = self.banks[..index].iter().enumerate();
+            if let Some((_, other)) = earlier.find(|(i, other)| other.program.unwrap_or(*i as u8) == program) {
+                bail!("Banks `{}` and `{}` are both selected by Program Change {program}", other.name, bank.name);
+            }
+        }
+        for (index, adc) in self.adcs.iter().enumerate() {
+            if self.adc_index(&adc.name) != Some(index) {
+                bail!("ADC `{}` is declared more than once", adc.name);
+            }
+            if adc.sample_rate <= 0.0 {
+                bail!("ADC `{}` needs a positive `sample_rate`", adc.name);
+            }
+            if let AdcKind::Ads1115(ads1115) = &adc.kind {
+                ads1115.validate()?;
+                // Each reading is a conversion of its own
+                let potentiometers = self
+                    .controls
+                    .iter()
+                    .filter(|control| matches!(control, ControlConfig::Potentiometer(pot) if pot.adc == adc.name))
+                    .count();
+                if potentiometers as f64 * adc.sample_rate > ads1115.data_rate as f64 {
+                    bail!(
+                        "ADC `{}` can't make {potentiometers} readings {} times a second with a `data_rate` of {}",
+                        adc.name,
+                        adc.sample_rate,
+                        ads1115.data_rate
+                    );
+                }
+            }
+        }
+        for (index, expander) in self.expanders.iter().enumerate() {
+            if self.expander_index(&expander.name) != Some(index) {
+                bail!("Expander `{}` is declared more than once", expander.name);
+            }
+            expander.validate()?;
+        }
+        for (index, output) in self.outputs.iter().enumerate() {
+            if self.output_index(&output.name) != Some(index) {
+                bail!("Output `{}` is declared more than once", output.name);
+            }
+        }
+        for control in self.controls.iter() {
+            control.validate()?;
+            match control {
+                ControlConfig::Button(button) => self.validate_pin(&button.pin, button.pull())?,
+                ControlConfig::RotaryEncoder(encoder) => {
+                    self.validate_pin(&encoder.pin_a, encoder.pull)?;
+                    self.validate_pin(&encoder.pin_b, encoder.pull)?;
+                    if let Some(pin) = &encoder.pin_switch {
+                        self.validate_pin(pin, encoder.switch_pull)?;
+                    }
+                }
+                // Rows and columns are native pins
+                ControlConfig::Potentiometer(_) | ControlConfig::ButtonMatrix(_) => {}
+            }
+
+            if !self.outputs.is_empty()
+                && let Some(name) = control.outputs().into_iter().flatten().find(|name| self.output_index(name).is_none())
+            {
+                bail!("Output `{name}` is not declared in `outputs`");
+            }
+
+            let (mut layer_names, mut bank_names): (Vec<&String>, Vec<&String>) = match control {
+                ControlConfig::RotaryEncoder(encoder) => (encoder.layers.keys().collect(), encoder.banks.keys().collect()),
+                ControlConfig::Potentiometer(potentiometer) => (potentiometer.layers.keys().collect(), potentiometer.banks.keys().collect()),
+                _ => (Vec::new(), Vec::new()),
+            };
+            for (description, key) in control.keys() {
+                layer_names.extend(key.layers.keys().chain(key.action.iter().filter_map(ButtonAction::layer)));
+                bank_names.extend(key.banks.keys().chain(key.action.iter().filter_map(ButtonAction::bank)));
+                if matches!(key.action, Some(ButtonAction::NextBank | ButtonAction::PrevBank)) && self.banks.is_empty() {
+                    bail!("{description} switches banks but there are no `banks`");
+                }
+                if key.restore && self.state_file.is_none() {
+                    bail!("{description} has `restore` set but there is no `state_file`");
+                }
+            }
+            if let Some(name) = layer_names.into_iter().find(|name| self.layer_index(name).is_none()) {
+                bail!("Layer `{name}` is not declared in `layers`");
+            }
+            if let Some(name) = bank_names.into_iter().find(|name| self.bank_index(name).is_none()) {
+                bail!("Bank `{name}` is not declared in `banks`");
+            }
+
+            if let ControlConfig::Potentiometer(potentiometer) = control {
+                let Some(adc) = self.adc_index(&potentiometer.adc).map(|index| &self.adcs[index]) else {
+                    bail!("ADC `{}` is not declared in `adcs`", potentiometer.adc);
+                };
+                if potentiometer.input >= adc.kind.inputs() {
+                    bail!("ADC `{}` has no input {}", adc.name, potentiometer.input);
+                }
+                let raw_max = potentiometer.raw_max.unwrap_or(adc.kind.full_scale());
+                if potentiometer.raw_min.unwrap_or(0) == raw_max {
+                    bail!("Potentiometer `raw_min` and `raw_max` can't be equal");
+                }
+            }
+        }
+        for led in self.leds.iter() {
+            led.validate()?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let config: Config = toml::from_str(s)?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expander(chip: &str, pin: u8) -> PinId {
+        PinId::Expander { chip: chip.to_string(), pin }
+    }
+
+    #[test]
+    fn parse_pin() {
+        for (s, pin) in [
+            ("17", PinId::Gpio(17)),
+            ("pads:A0", expander("pads", 0)),
+            ("pads:a7", expander("pads", 7)),
+            ("pads:B0", expander("pads", 8)),
+            ("pads:b7", expander("pads", 15)),
+            ("pads:0", expander("pads", 0)),
+            ("pads:15", expander("pads", 15)),
+        ] {
+            assert_eq!(s.parse::<PinId>().unwrap(), pin, "{s}");
+        }
+        for s in ["", "pads", "-1", "256", "pads:", "pads:C0", "pads:A8", "pads:B", "pads:-1", "pads:256"] {
+            assert!(s.parse::<PinId>().is_err(), "{s}");
+        }
+    }
+
+    fn button_on(pin: &str) -> Result<Config> {
+        format!(
+            r#"
+            [[expanders]]
+            name = "pads"
+            type = "mcp23017"
+            interrupt = 4
+
+            [[controls]]
+            type = "Button"
+            pin = "{pin}"
+            note = 36
+            "#
+        )
+        .parse()
+    }
+
+    #[test]
+    fn program_numbers_unique() {
+        let banks = |programs: &str| format!("banks = [{programs}]\ncontrols = []").parse::<Config>();
+        assert!(banks(r#"{ name = "a" }, { name = "b" }"#).is_ok());
+        assert!(banks(r#"{ name = "a", program = 1 }, { name = "b", program = 0 }"#).is_ok());
+        // Defaults to the bank's position
+        assert!(banks(r#"{ name = "a" }, { name = "b", program = 0 }"#).is_err());
+        assert!(banks(r#"{ name = "a", program = 1 }, { name = "b" }"#).is_err());
+        assert!(banks(r#"{ name = "a", program = 5 }, { name = "b", program = 5 }"#).is_err());
+    }
+
+    #[test]
+    fn expander_pin_checked_against_expanders() {
+        assert!(button_on("pads:B7").is_ok());
+        assert!(button_on("pads:16").is_err());
+        assert!(button_on("keys:A0").is_err());
+    }
+}
@@ -0,0 +1,386 @@
+use crate::backend::Level;
+use crate::button::ButtonOutput;
+use crate::config::{Acceleration, Config, EncoderMapping, PushTurn, RelativeEncoding, RotaryEncoderConfig};
+use crate::mapping::{Active, Mappings};
+use crate::midi::{self, Incoming};
+use std::collections::HashMap;
+use tokio::time::Instant;
+
+// Gray code state machine transition table for rotary encoders
+const TRANSITION_TABLE: [i8; 16] = [
+    // prev: 00
+     0,  1, -1,  0,
+    // prev: 01
+    -1,  0,  0,  1,
+    // prev: 10
+     1,  0,  0, -1,
+    // prev: 11
+     0, -1,  1,  0,
+];
+
+fn state_bits(a: Level, b: Level) -> u8 {
+    ((a == Level::High) as u8) << 1 | ((b == Level::High) as u8)
+}
+
+// Largest delta every encoding can represent
+const MAX_RELATIVE_DELTA: i32 = 63;
+
+impl RelativeEncoding {
+    /// CC value for a signed `delta`, clamped to what the encoding can represent
+    pub fn encode(self, delta: i32) -> u8 {
+        let delta = delta.clamp(-MAX_RELATIVE_DELTA, MAX_RELATIVE_DELTA);
+        let value = match self {
+            RelativeEncoding::TwosComplement => delta.rem_euclid(128),
+            RelativeEncoding::BinaryOffset => 64 + delta,
+            RelativeEncoding::SignedBit if delta < 0 => 0x40 | -delta,
+            RelativeEncoding::SignedBit => delta,
+        };
+        value as u8
+    }
+}
+
+/// Limits for absolute encoder values
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ValueRange {
+    pub min: u8,
+    pub max: u8,
+    pub wrap: bool,
+}
+
+impl ValueRange {
+    /// Move `value` by `delta`, clamping or wrapping at the limits
+    pub fn apply(&self, value: u8, delta: i32) -> u8 {
+        let (min, max) = (self.min as i32, self.max as i32);
+        let next = value as i32 + delta;
+        let next = if self.wrap {
+            min + (next - min).rem_euclid(max - min + 1)
+        } else {
+            next.clamp(min, max)
+        };
+        next as u8
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct RotaryEncoderState {
+    prev_state: u8,
+    accum: i8,
+    // Gray code transitions per detent
+    steps_per_detent: i8,
+    reverse: bool,
+    // Time and direction of the previous detent, for acceleration
+    last_detent: Option<(Instant, i8)>,
+}
+
+impl RotaryEncoderState {
+    pub fn new(a: Level, b: Level, steps_per_detent: u8, reverse: bool) -> Self {
+        Self {
+            prev_state: state_bits(a, b),
+            accum: 0,
+            steps_per_detent: steps_per_detent as i8,
+            reverse,
+            last_detent: None,
+        }
+    }
+
+    /// Number of steps a detent in `direction` moves, accelerated by how soon it follows
+    /// the previous detent in the same direction
+    pub fn detent_steps(&mut self, direction: i8, now: Instant, acceleration: Option<&Acceleration>) -> u8 {
+        let previous = self.last_detent.replace((now, direction));
+        match (acceleration, previous) {
+            (Some(acceleration), Some((at, previous_direction))) if previous_direction == direction => acceleration.step(now - at),
+            _ => 1,
+        }
+    }
+
+    pub fn update(&mut self, a: Level, b: Level) -> Option<i8> {
+        let new_state = state_bits(a, b);
+
+        if new_state == self.prev_state {
+            return None; // No change, ignore
+        }
+
+        let index = (self.prev_state << 2) | new_state;
+        let movement = TRANSITION_TABLE[index as usize];
+        self.accum += movement;
+        self.prev_state = new_state;
+
+        if self.accum.abs() >= self.steps_per_detent {
+            let step = self.accum.signum();
+            self.accum = 0;
+            Some(if self.reverse { -step } else { step })
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PushTurnMode {
+    AlternateCc,
+    Step(u8),
+}
+
+/// Where turns are sent, with a zero-based channel
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Target {
+    pub channel: u8,
+    pub cc: u8,
+}
+
+impl Target {
+    /// Resolve a control's `cc` and `channel` and its layer and bank mappings, where a
+    /// mapping without a channel keeps the control's
+    pub fn mappings(
+        cc: u8,
+        channel: Option<u8>,
+        layers: &HashMap<String, EncoderMapping>,
+        banks: &HashMap<String, EncoderMapping>,
+        global: &Config,
+    ) -> Mappings<Target> {
+        // Stored zero-based, as sent in the status byte
+        let channel = channel.unwrap_or(global.channel) - 1;
+        let remapped = |mappings: &HashMap<String, EncoderMapping>, name: &String| {
+            mappings.get(name).map(|mapping| Target { channel: mapping.channel.map_or(channel, |c| c - 1), cc: mapping.cc })
+        };
+        Mappings::new(
+            Some(Target { channel, cc }),
+            global.layers.iter().map(|layer| remapped(layers, layer)).collect(),
+            global.banks.iter().map(|bank| remapped(banks, &bank.name)).collect(),
+        )
+    }
+}
+
+// Absolute values are kept per target slot, or `None` for the alternate target. Each bank
+// mapping has a slot of its own, while the base and layer mappings are shared by every bank.
+type ValueKey = Option<usize>;
+
+/// A configured rotary encoder and its optional push switch
+#[derive(Debug)]
+pub(crate) struct Encoder {
+    pub state: RotaryEncoderState,
+    targets: Mappings<Target>,
+    // Target for push-and-turn with an alternate CC
+    alternate: Option<Target>,
+    values: HashMap<ValueKey, u8>,
+    initial_value: u8,
+    // Encoding of relative deltas, `None` for absolute values
+    relative: Option<RelativeEncoding>,
+    range: ValueRange,
+    acceleration: Option<Acceleration>,
+    step: u8,
+    switch: Option<ButtonOutput>,
+    push_turn: Option<PushTurnMode>,
+    pressed: bool,
+    turned_while_pressed: bool,
+}
+
+impl Encoder {
+    pub fn new(config: &RotaryEncoderConfig, global: &Config, a: Level, b: Level) -> Self {
+        let default_channel = global.channel;
+        // Stored zero-based, as sent in the status byte
+        let channel = config.channel.unwrap_or(default_channel) - 1;
+
+        let targets = Target::mappings(config.cc, config.channel, &config.layers, &config.banks, global);
+
+        let (push_turn, alternate) = match config.push_turn {
+            Some(PushTurn::AlternateCc { cc, channel: alternate_channel }) => (
+                Some(PushTurnMode::AlternateCc),
+                Some(Target { channel: alternate_channel.map_or(channel, |c| c - 1), cc }),
+            ),
+            Some(PushTurn::Step { step }) => (Some(PushTurnMode::Step(step)), None),
+            None => (None, None),
+        };
+
+        Self {
+            state: RotaryEncoderState::new(a, b, config.steps_per_detent, config.reverse),
+            targets,
+            alternate,
+            values: HashMap::new(),
+            initial_value: config.initial_value.unwrap_or(64).clamp(config.min, config.max),
+            relative: config.relative_value.then_some(config.relative_encoding),
+            range: ValueRange { min: config.min, max: config.max, wrap: config.wrap },
+            acceleration: config.acceleration.clone(),
+            step: config.step,
+            switch: config.switch.as_ref().map(|switch| ButtonOutput::new(switch, default_channel)),
+            push_turn,
+            pressed: false,
+            turned_while_pressed: false,
+        }
+    }
+
+    /// Feed new A/B levels with the `active` layer and bank, returning the message for a completed detent
+    pub fn update(&mut self, a: Level, b: Level, now: Instant, active: Active) -> Option<[u8; 3]> {
+        let dir = self.state.update(a, b)?;
+        let steps = self.state.detent_steps(dir, now, self.acceleration.as_ref()) as i32;
+
+        let push_turn = if self.pressed { self.push_turn } else { None };
+        if self.pressed {
+            self.turned_while_pressed = true;
+        }
+
+        let slot = self.targets.slot(active);
+        let (target, key, step) = match push_turn {
+            Some(PushTurnMode::AlternateCc) => (self.alternate?, None, self.step),
+            Some(PushTurnMode::Step(step)) => (*self.targets.get(slot)?, Some(slot), step),
+            None => (*self.targets.get(slot)?, Some(slot), self.step),
+        };
+        let delta = dir as i32 * steps * step as i32;
+
+        if let Some(encoding) = self.relative {
+            return Some(midi::control_change(target.channel, target.cc, encoding.encode(delta)));
+        }
+
+        let value = self.values.entry(key).or_insert(self.initial_value);
+        let next = self.range.apply(*value, delta);
+        if next == *value {
+            return None;
+        }
+        *value = next;
+        Some(midi::control_change(target.channel, target.cc, next))
+    }
+
+    /// Position of the value being turned between `min` and `max`, from 0.0 to 1.0,
+    /// or `None` for relative encoders
+    pub fn position(&self, active: Active) -> Option<f64> {
+        if self.relative.is_some() {
+            return None;
+        }
+        let key = match self.push_turn {
+            Some(PushTurnMode::AlternateCc) if self.pressed => None,
+            _ => Some(self.targets.slot(active)),
+        };
+        let value = self.values.get(&key).copied().unwrap_or(self.initial_value);
+        let ValueRange { min, max, .. } = self.range;
+        Some(if max == min { 1.0 } else { (value - min) as f64 / (max - min) as f64 })
+    }
+
+    /// Take a value set elsewhere, such as by the host, for every target on the incoming CC
+    pub fn feedback(&mut self, incoming: Incoming) {
+        let Incoming::ControlChange { channel, cc, value } = incoming else { return };
+        if self.relative.is_some() {
+            return;
+        }
+
+        let slots = self.targets.slots().map(|(slot, target)| (Some(slot), *target));
+        let alternate = self.alternate.map(|target| (None, target));
+        let matching: Vec<Option<usize>> = slots
+            .chain(alternate)
+            .filter(|(_, target)| (target.channel, target.cc) == (channel, cc))
+            .map(|(slot, _)| slot)
+            .collect();
+
+        let value = value.clamp(self.range.min, self.range.max);
+        for slot in matching {
+            self.values.insert(slot, value);
+        }
+    }
+
+    /// Handle the push switch changing state, returning the messages to send.
+    ///
+    /// With push-and-turn the switch message is held back until release, and dropped
+    /// if the encoder was turned in between.
+    pub fn switch(&mut self, pressed: bool) -> Vec<[u8; 3]> {
+        let was_turned = self.turned_while_pressed;
+        self.pressed = pressed;
+        self.turned_while_pressed = false;
+
+        let Some(output) = &self.switch else { return Vec::new() };
+        match (self.push_turn.is_some(), pressed) {
+            (false, true) => vec![output.on()],
+            (false, false) => vec![output.off()],
+            (true, true) => Vec::new(),
+            (true, false) if was_turned => Vec::new(),
+            (true, false) => vec![output.on(), output.off()],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn relative_encodings() {
+        use RelativeEncoding::*;
+        // Deltas beyond 63 clamp to it
+        for (encoding, delta, value) in [
+            (TwosComplement, 1, 1),
+            (TwosComplement, -1, 127),
+            (TwosComplement, 63, 63),
+            (TwosComplement, -63, 65),
+            (TwosComplement, 100, 63),
+            (TwosComplement, -100, 65),
+            (BinaryOffset, 1, 65),
+            (BinaryOffset, -1, 63),
+            (BinaryOffset, 63, 127),
+            (BinaryOffset, -63, 1),
+            (BinaryOffset, 100, 127),
+            (BinaryOffset, -100, 1),
+            (SignedBit, 1, 1),
+            (SignedBit, -1, 65),
+            (SignedBit, 63, 63),
+            (SignedBit, -63, 127),
+            (SignedBit, 100, 63),
+            (SignedBit, -100, 127),
+        ] {
+            assert_eq!(encoding.encode(delta), value, "{encoding:?} {delta}");
+        }
+    }
+
+    #[test]
+    fn value_range() {
+        let clamp = ValueRange { min: 10, max: 20, wrap: false };
+        let wrap = ValueRange { wrap: true, ..clamp };
+        for (range, value, delta, next) in [
+            (clamp, 15, 3, 18),
+            (clamp, 19, 3, 20),
+            (clamp, 11, -3, 10),
+            (clamp, 20, 1, 20),
+            (wrap, 15, -3, 12),
+            (wrap, 20, 1, 10),
+            (wrap, 19, 3, 11),
+            (wrap, 10, -1, 20),
+            (wrap, 11, -3, 19),
+        ] {
+            assert_eq!(range.apply(value, delta), next, "{range:?} {value} {delta:+}");
+        }
+        let full = ValueRange { min: 0, max: 127, wrap: true };
+        assert_eq!(full.apply(127, 1), 0);
+        assert_eq!(full.apply(0, -1), 127);
+    }
+
+    #[test]
+    fn acceleration_curve() {
+        let linear = Acceleration { slow_ms: 100, fast_ms: 10, max_step: 8, exponent: 1.0 };
+        let curved = Acceleration { exponent: 2.0, ..linear.clone() };
+        let single = Acceleration { max_step: 1, ..linear.clone() };
+        for (acceleration, ms, step) in [
+            // Clamped past `slow_ms` and `fast_ms`
+            (&linear, 500, 1),
+            (&linear, 100, 1),
+            (&linear, 55, 5),
+            (&linear, 10, 8),
+            (&linear, 0, 8),
+            // Halfway along the curve is a quarter of the way up it
+            (&curved, 55, 3),
+            (&curved, 10, 8),
+            (&single, 0, 1),
+        ] {
+            assert_eq!(acceleration.step(Duration::from_millis(ms)), step, "{acceleration:?} {ms}ms");
+        }
+    }
+
+    #[test]
+    fn acceleration_resets_on_direction_change() {
+        let acceleration = Acceleration { slow_ms: 100, fast_ms: 10, max_step: 8, exponent: 1.0 };
+        let mut state = RotaryEncoderState::new(Level::High, Level::High, 4, false);
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+        for (direction, at, steps) in [(1, 0, 1), (1, 10, 8), (-1, 20, 1), (-1, 30, 8), (-1, 200, 1)] {
+            assert_eq!(state.detent_steps(direction, ms(at), Some(&acceleration)), steps, "{direction:+} at {at}ms");
+        }
+        assert_eq!(state.detent_steps(-1, ms(210), None), 1);
+    }
+}
@@ -0,0 +1,717 @@
+use crate::backend::{Adc, Edge, ExpanderPins, GpioBackend, InputPin, Inverted, Level, Pull, TristatePin};
+use crate::button::{Action, Button};
+use crate::config::{BankConfig, Config, ControlConfig, Decoding, PinId};
+use crate::encoder::{Encoder, Target};
+use crate::led::{Led, Ring};
+use crate::mapping::{Active, Mappings};
+use crate::matrix::Matrix;
+use crate::midi::{self, Incoming, MidiSink, Outputs};
+use crate::potentiometer::Potentiometer;
+use crate::state::StateFile;
+use anyhow::{Result, anyhow, bail};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, sleep, sleep_until};
+
+pub const DEFAULT_POLLING_RATE: f64 = 4000.0;
+
+// Time for the columns to follow a newly selected matrix row
+const MATRIX_SETTLE: Duration = Duration::from_micros(10);
+
+#[derive(Debug)]
+enum Event {
+    Edge { pin: PinId, edge: Edge },
+    EncoderLevels { control: usize, a: Level, b: Level },
+    Potentiometer { control: usize, value: u8 },
+    MatrixKey { control: usize, pressed: bool },
+}
+
+// An interrupt encoder's A and B, set once both are claimed. Weak, as the pins own the
+// callbacks that read them.
+type InterruptPins = Arc<Mutex<Option<(Weak<dyn InputPin>, Weak<dyn InputPin>)>>>;
+
+#[derive(Debug)]
+struct PolledEncoder {
+    control: usize,
+    pin_a: Arc<dyn InputPin>,
+    pin_b: Arc<dyn InputPin>,
+    // Last levels seen by the poller
+    levels: (Level, Level),
+}
+
+#[derive(Debug)]
+struct AdcInput {
+    control: usize,
+    input: u8,
+    potentiometer: Potentiometer,
+}
+
+#[derive(Debug)]
+struct MatrixScan {
+    rows: Vec<Box<dyn TristatePin>>,
+    columns: Vec<Box<dyn InputPin>>,
+    // Level driving the selected row
+    select: Level,
+    matrix: Matrix,
+    // Control of each key, row by row
+    keys: Vec<Option<usize>>,
+    interval: Duration,
+}
+
+#[derive(Debug)]
+enum Control {
+    Button {
+        button: Button,
+        // Keep alive for interrupt, none for matrix keys which are scanned
+        _pin: Option<Box<dyn InputPin>>,
+    },
+    RotaryEncoder {
+        encoder: Encoder,
+        // Keep alive for interrupts or while the poller reads them
+        _pin_a: Arc<dyn InputPin>,
+        _pin_b: Arc<dyn InputPin>,
+        // Keep alive for interrupt
+        _pin_switch: Option<Box<dyn InputPin>>,
+        ring: Option<Ring>,
+    },
+    // Read by the ADC's thread
+    Potentiometer {
+        targets: Mappings<Target>,
+    },
+}
+
+fn send(outputs: &mut Outputs, routes: &[usize], message: &[u8]) {
+    if cfg!(feature = "print") {
+        println!("Sending {message:02X?}");
+    }
+
+    outputs.send(routes, message);
+}
+
+/// Control state owned by the engine's event loop
+struct Controls {
+    controls: Vec<Control>,
+    // Indices of the outputs each control sends to, empty for all
+    routes: Vec<Vec<usize>>,
+    pin_map: HashMap<PinId, usize>,
+    leds: Vec<Led>,
+    // Keep alive for interrupts
+    _expanders: HashMap<String, ExpanderPins>,
+    state_file: Option<StateFile>,
+    layers: Vec<String>,
+    banks: Vec<BankConfig>,
+    active: Active,
+    // Control and layer of each momentary shift held, in the order they were pressed
+    held_shifts: Vec<(usize, usize)>,
+    // Layer of the last latching shift turned on
+    latched_layer: Option<usize>,
+}
+
+impl Controls {
+    /// Send the state of restored toggles so the receiver matches them
+    fn send_restored(&self, outputs: &mut Outputs) {
+        for (index, control) in self.controls.iter().enumerate() {
+            if let Control::Button { button, .. } = control
+                && button.restore
+            {
+                for message in button.latched_messages() {
+                    send(outputs, &self.routes[index], &message);
+                }
+            }
+        }
+    }
+
+    /// Show encoder values on their rings, only writing the LEDs that changed
+    fn refresh_rings(&mut self) {
+        for control in self.controls.iter_mut() {
+            if let Control::RotaryEncoder { encoder, ring: Some(ring), .. } = control
+                && let Some(position) = encoder.position(self.active)
+            {
+                ring.show(position);
+            }
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.controls
+            .iter()
+            .filter_map(|control| match control {
+                Control::Button { button, .. } => button.deadline(),
+                Control::RotaryEncoder { .. } | Control::Potentiometer { .. } => None,
+            })
+            .min()
+    }
+
+    fn expire(&mut self, outputs: &mut Outputs) {
+        let now = Instant::now();
+        for index in 0..self.controls.len() {
+            if let Control::Button { button, .. } = &mut self.controls[index] {
+                let messages = button.expire(now);
+                self.button_changed(index, messages, outputs);
+            }
+        }
+    }
+
+    // Send a button's messages and save its toggle state if needed
+    fn button_changed(&mut self, index: usize, messages: Vec<[u8; 3]>, outputs: &mut Outputs) {
+        if messages.is_empty() {
+            return;
+        }
+        for message in messages.iter() {
+            send(outputs, &self.routes[index], message);
+        }
+        self.save_toggles(index);
+    }
+
+    fn save_toggles(&mut self, index: usize) {
+        if let Control::Button { button, .. } = &self.controls[index]
+            && button.restore
+            && let Some(state_file) = self.state_file.as_mut()
+        {
+            for (key, latched) in button.toggles() {
+                if state_file.toggle(key) != Some(latched)
+                    && let Err(e) = state_file.set_toggle(key, latched)
+                {
+                    eprintln!("Failed to save toggle state: {e}");
+                }
+            }
+        }
+    }
+
+    // Switch layers or banks for an action button being pressed or released
+    fn action(&mut self, index: usize, pressed: bool) {
+        let Control::Button { button, .. } = &self.controls[index] else { return };
+        let bank_count = self.banks.len();
+        let current_bank = self.active.bank.unwrap_or(0);
+        match button.action {
+            Some(Action::Shift(shift)) => {
+                match (shift.latching, pressed) {
+                    (true, true) if self.latched_layer == Some(shift.layer) => self.latched_layer = None,
+                    (true, true) => self.latched_layer = Some(shift.layer),
+                    (false, true) => self.held_shifts.push((index, shift.layer)),
+                    (false, false) => self.held_shifts.retain(|&(control, _)| control != index),
+                    (true, false) => {}
+                }
+                // The last momentary shift still held wins, then a latched layer
+                let active_layer = self.held_shifts.last().map(|&(_, layer)| layer).or(self.latched_layer);
+
+                if cfg!(feature = "print") && active_layer != self.active.layer {
+                    println!("Active layer: {:?}", active_layer.map(|layer| &self.layers[layer]));
+                }
+                self.active.layer = active_layer;
+            }
+            Some(Action::NextBank) if pressed => self.select_bank((current_bank + 1) % bank_count),
+            Some(Action::PrevBank) if pressed => self.select_bank((current_bank + bank_count - 1) % bank_count),
+            Some(Action::SelectBank(bank)) if pressed => self.select_bank(bank),
+            _ => {}
+        }
+    }
+
+    fn select_bank(&mut self, bank: usize) {
+        if cfg!(feature = "print") && self.active.bank != Some(bank) {
+            println!("Active bank: {}", self.banks[bank].name);
+        }
+        self.active.bank = Some(bank);
+    }
+
+    /// Handle a message from the MIDI input, selecting banks and keeping controls in
+    /// sync with the host
+    fn midi(&mut self, message: &[u8]) {
+        let Some(incoming) = Incoming::parse(message) else { return };
+        if cfg!(feature = "print") {
+            println!("Received {incoming:?}");
+        }
+
+        if let Incoming::ProgramChange { program, .. } = incoming {
+            if let Some(bank) = self.banks.iter().enumerate().position(|(index, bank)| bank.program.unwrap_or(index as u8) == program) {
+                self.select_bank(bank);
+            }
+            return;
+        }
+
+        for led in self.leds.iter_mut() {
+            led.handle(incoming);
+        }
+        for index in 0..self.controls.len() {
+            match &mut self.controls[index] {
+                Control::Button { button, .. } => {
+                    if button.feedback(incoming) {
+                        self.save_toggles(index);
+                    }
+                }
+                Control::RotaryEncoder { encoder, .. } => encoder.feedback(incoming),
+                // Nothing to move, the pot is wherever it was left
+                Control::Potentiometer { .. } => {}
+            }
+        }
+    }
+
+    /// A button, matrix key or encoder switch was pressed or released
+    fn press(&mut self, index: usize, pressed: bool, now: Instant, outputs: &mut Outputs) {
+        match &mut self.controls[index] {
+            Control::Button { button, .. } if button.action.is_some() => self.action(index, pressed),
+            Control::Button { button, .. } => {
+                let messages = button.edge(pressed, now, self.active);
+                self.button_changed(index, messages, outputs);
+            }
+            Control::RotaryEncoder { encoder, .. } => {
+                for message in encoder.switch(pressed) {
+                    send(outputs, &self.routes[index], &message);
+                }
+            }
+            Control::Potentiometer { .. } => {}
+        }
+    }
+
+    fn handle(&mut self, event: Event, outputs: &mut Outputs) {
+        let now = Instant::now();
+        match event {
+            Event::Edge { pin, edge } => {
+                if cfg!(feature = "print") {
+                    println!("Event on pin {pin}, {edge:?}");
+                }
+
+                let Some(&index) = self.pin_map.get(&pin) else { return };
+                self.press(index, edge == Edge::Rising, now, outputs);
+            }
+            Event::MatrixKey { control, pressed } => self.press(control, pressed, now, outputs),
+            Event::Potentiometer { control, value } => {
+                if let Control::Potentiometer { targets } = &self.controls[control]
+                    && let Some(target) = targets.get(targets.slot(self.active))
+                {
+                    send(outputs, &self.routes[control], &midi::control_change(target.channel, target.cc, value));
+                }
+            }
+            Event::EncoderLevels { control, a, b } => {
+                if let Control::RotaryEncoder { encoder, .. } = &mut self.controls[control]
+                    && let Some(message) = encoder.update(a, b, now, self.active)
+                {
+                    send(outputs, &self.routes[control], &message);
+                }
+            }
+        }
+    }
+}
+
+/// Claim an input that reads high while active
+fn claim_input(
+    backend: &mut dyn GpioBackend,
+    expanders: &HashMap<String, ExpanderPins>,
+    pin: &PinId,
+    pull: Pull,
+    active_low: bool,
+) -> Result<Box<dyn InputPin>> {
+    let input = match pin {
+        PinId::Gpio(pin) => backend.input(*pin, pull)?,
+        PinId::Expander { chip, pin } => expanders[chip].input(*pin, pull)?,
+    };
+    Ok(if active_low { Box::new(Inverted(input)) } else { input })
+}
+
+/// MIDI connections, owned by the event loop while running
+struct Midi {
+    outputs: Outputs,
+    input: mpsc::UnboundedReceiver<Vec<u8>>,
+}
+
+struct Running {
+    shutdown: oneshot::Sender<()>,
+    event_loop: JoinHandle<Midi>,
+    poller: JoinHandle<()>,
+    // ADC readers and matrix scanners
+    stop_threads: Arc<AtomicBool>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+/// Turns GPIO activity into MIDI messages according to a [`Config`]
+pub struct Engine {
+    config: Config,
+    backend: Box<dyn GpioBackend>,
+    midi: Option<Midi>,
+    midi_input: mpsc::UnboundedSender<Vec<u8>>,
+    polling_rate: f64,
+    running: Option<Running>,
+}
+
+impl Engine {
+    /// Create an engine sending every control to `sink`
+    pub fn new(config: Config, backend: impl GpioBackend + 'static, sink: impl MidiSink + 'static) -> Self {
+        let mut outputs = Outputs::new();
+        outputs.add("default", sink);
+        Self::with_outputs(config, backend, outputs)
+    }
+
+    /// Create an engine routing controls to `outputs` by name
+    pub fn with_outputs(config: Config, backend: impl GpioBackend + 'static, outputs: Outputs) -> Self {
+        let (midi_input, input) = mpsc::unbounded_channel();
+        Self {
+            config,
+            backend: Box::new(backend),
+            midi: Some(Midi { outputs, input }),
+            midi_input,
+            polling_rate: DEFAULT_POLLING_RATE,
+            running: None,
+        }
+    }
+
+    /// Polling rate in hz for rotary encoders with `decoding = "polling"`
+    pub fn set_polling_rate(&mut self, polling_rate: f64) {
+        self.polling_rate = polling_rate;
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.is_some()
+    }
+
+    /// Sender for incoming MIDI messages, such as Program Change to select a bank.
+    /// Stays valid when the engine is stopped and started again.
+    pub fn midi_input(&self) -> mpsc::UnboundedSender<Vec<u8>> {
+        self.midi_input.clone()
+    }
+
+    /// Claim the configured pins and start processing events. Must be called
+    /// from within a tokio runtime.
+    pub fn start(&mut self) -> Result<()> {
+        if self.running.is_some() {
+            bail!("Engine is already running");
+        }
+
+        let outputs = &self.midi.as_ref().expect("MIDI is returned when the engine stops").outputs;
+        let routes = self
+            .config
+            .controls
+            .iter()
+            .flat_map(ControlConfig::outputs)
+            .map(|names| {
+                names.iter().map(|name| outputs.index(name).ok_or_else(|| anyhow!("No MIDI output called `{name}`"))).collect()
+            })
+            .collect::<Result<Vec<Vec<usize>>>>()?;
+
+        // Unbounded so a burst of turns never drops an edge, which would lose its detent
+        let (tx, rx) = mpsc::unbounded_channel::<Event>();
+        let mut controls = Vec::new();
+        let mut pin_map = HashMap::new();
+        let mut encoders = Vec::new();
+        let mut scans = Vec::new();
+        let mut adc_inputs: Vec<Vec<AdcInput>> = self.config.adcs.iter().map(|_| Vec::new()).collect();
+        let state_file = self.config.state_file.clone().map(StateFile::load).transpose()?;
+
+        let mut expanders = HashMap::new();
+        for expander in self.config.expanders.iter() {
+            // INTA/INTB are active low
+            let interrupt = self.backend.input(expander.interrupt, Pull::Up)?;
+            let driver = self.backend.expander(expander)?;
+            expanders.insert(expander.name.clone(), ExpanderPins::new(&expander.name, driver, interrupt)?);
+        }
+
+        for control in self.config.controls.iter() {
+            match control {
+                ControlConfig::Button(button) => {
+                    let pin = button.pin.clone();
+                    let mut gpio_in_pin = claim_input(self.backend.as_mut(), &expanders, &pin, button.pull(), button.active_low())?;
+                    let debounce = button.debounce_ms.map(Duration::from_millis).or(Some(Duration::from_millis(5)));
+                    let tx_clone = tx.clone();
+                    let event_pin = pin.clone();
+                    gpio_in_pin.subscribe(debounce, Box::new(move |edge| {
+                        let _ = tx_clone.send(Event::Edge { pin: event_pin.clone(), edge });
+                    }))?;
+                    pin_map.insert(pin, controls.len());
+                    controls.push(Control::Button {
+                        button: Button::new(&button.key, &button.pin.to_string(), &self.config, state_file.as_ref()),
+                        _pin: Some(gpio_in_pin),
+                    });
+                }
+                ControlConfig::RotaryEncoder(encoder) => {
+                    let control = controls.len();
+                    let mut a = claim_input(self.backend.as_mut(), &expanders, &encoder.pin_a, encoder.pull, encoder.active_low)?;
+                    let mut b = claim_input(self.backend.as_mut(), &expanders, &encoder.pin_b, encoder.pull, encoder.active_low)?;
+                    let levels = (a.read(), b.read());
+
+                    // Each pin's interrupt runs on its own thread, so their edges can arrive out of
+                    // order. Instead, either edge reads both pins, under a lock so the readings
+                    // are sent in the order they were taken.
+                    let interrupt_pins = InterruptPins::default();
+                    if encoder.decoding == Decoding::Interrupt {
+                        for pin in [&mut a, &mut b] {
+                            let (tx_clone, pins) = (tx.clone(), interrupt_pins.clone());
+                            // No debounce, the Gray code state machine rejects bounces
+                            pin.subscribe(None, Box::new(move |_| {
+                                let pins = pins.lock().unwrap();
+                                if let Some((a, b)) = pins.as_ref()
+                                    && let (Some(a), Some(b)) = (a.upgrade(), b.upgrade())
+                                {
+                                    let _ = tx_clone.send(Event::EncoderLevels { control, a: a.read(), b: b.read() });
+                                }
+                            }))?;
+                        }
+                    }
+                    let a: Arc<dyn InputPin> = Arc::from(a);
+                    let b: Arc<dyn InputPin> = Arc::from(b);
+                    *interrupt_pins.lock().unwrap() = Some((Arc::downgrade(&a), Arc::downgrade(&b)));
+
+                    let pin_switch = match &encoder.pin_switch {
+                        Some(pin) => {
+                            let mut switch =
+                                claim_input(self.backend.as_mut(), &expanders, pin, encoder.switch_pull, encoder.switch_active_low())?;
+                            let tx_clone = tx.clone();
+                            let event_pin = pin.clone();
+                            switch.subscribe(Some(Duration::from_millis(5)), Box::new(move |edge| {
+                                let _ = tx_clone.send(Event::Edge { pin: event_pin.clone(), edge });
+                            }))?;
+                            pin_map.insert(pin.clone(), control);
+                            Some(switch)
+                        }
+                        None => None,
+                    };
+
+                    let ring = match &encoder.ring {
+                        Some(ring) => {
+                            let pins = ring.pins.iter().map(|&pin| self.backend.output(pin)).collect::<Result<Vec<_>>>()?;
+                            Some(Ring::new(ring, pins))
+                        }
+                        None => None,
+                    };
+
+                    if encoder.decoding == Decoding::Polling {
+                        encoders.push(PolledEncoder { control, pin_a: a.clone(), pin_b: b.clone(), levels });
+                    }
+                    controls.push(Control::RotaryEncoder {
+                        encoder: Encoder::new(encoder, &self.config, levels.0, levels.1),
+                        _pin_a: a,
+                        _pin_b: b,
+                        _pin_switch: pin_switch,
+                        ring,
+                    });
+                }
+                ControlConfig::ButtonMatrix(matrix) => {
+                    let rows = matrix.rows.iter().map(|&pin| self.backend.tristate(pin)).collect::<Result<Vec<_>>>()?;
+                    let columns = matrix
+                        .columns
+                        .iter()
+                        .map(|&pin| claim_input(self.backend.as_mut(), &expanders, &PinId::Gpio(pin), matrix.pull, matrix.active_low()))
+                        .collect::<Result<Vec<_>>>()?;
+
+                    let mut keys = vec![None; rows.len() * columns.len()];
+                    for key in matrix.keys.iter() {
+                        let (row, column) = (key.row as usize, key.column as usize);
+                        keys[row * columns.len() + column] = Some(controls.len());
+                        // The pins of a key are unique to it, so identify it in the state file
+                        let id = format!("{}x{}", matrix.rows[row], matrix.columns[column]);
+                        controls.push(Control::Button {
+                            button: Button::new(&key.key, &id, &self.config, state_file.as_ref()),
+                            _pin: None,
+                        });
+                    }
+
+                    scans.push(MatrixScan {
+                        select: if matrix.active_low() { Level::Low } else { Level::High },
+                        matrix: Matrix::new(rows.len(), columns.len(), Duration::from_millis(matrix.debounce_ms), !matrix.diodes),
+                        rows,
+                        columns,
+                        keys,
+                        interval: Duration::from_secs_f64(1.0 / matrix.scan_rate),
+                    });
+                }
+                ControlConfig::Potentiometer(potentiometer) => {
+                    let adc = self.config.adc_index(&potentiometer.adc).expect("Validated ADC exists");
+                    let full_scale = self.config.adcs[adc].kind.full_scale();
+                    adc_inputs[adc].push(AdcInput {
+                        control: controls.len(),
+                        input: potentiometer.input,
+                        potentiometer: Potentiometer::new(potentiometer, full_scale),
+                    });
+                    controls.push(Control::Potentiometer {
+                        targets: Target::mappings(
+                            potentiometer.cc,
+                            potentiometer.channel,
+                            &potentiometer.layers,
+                            &potentiometer.banks,
+                            &self.config,
+                        ),
+                    });
+                }
+            }
+        }
+
+        let mut adcs = Vec::new();
+        for (config, inputs) in self.config.adcs.iter().zip(adc_inputs) {
+            if !inputs.is_empty() {
+                adcs.push((self.backend.adc(config)?, inputs, Duration::from_secs_f64(1.0 / config.sample_rate)));
+            }
+        }
+
+        let leds = self
+            .config
+            .leds
+            .iter()
+            .map(|led| {
+                Ok(if led.pwm {
+                    Led::pwm(led, self.config.channel, self.backend.pwm_output(led.pin, led.pwm_frequency, led.pwm_channel)?)
+                } else {
+                    Led::switched(led, self.config.channel, self.backend.output(led.pin)?)
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        if cfg!(feature = "print") {
+            println!("Using controls: {:?}", controls);
+        }
+
+        let stop_threads = Arc::new(AtomicBool::new(false));
+        let mut threads = Vec::new();
+        for (adc, inputs, interval) in adcs {
+            let (tx, stop) = (tx.clone(), stop_threads.clone());
+            threads.push(thread::spawn(move || read_adc(adc, inputs, tx, stop, interval)));
+        }
+        for scan in scans {
+            let (tx, stop) = (tx.clone(), stop_threads.clone());
+            threads.push(thread::spawn(move || scan_matrix(scan, tx, stop)));
+        }
+
+        let polling_sleep = Duration::from_secs_f64(1.0 / self.polling_rate);
+        let poller = tokio::spawn(poll_encoders(encoders, tx, polling_sleep));
+
+        let (shutdown, shutdown_rx) = oneshot::channel();
+        let midi = self.midi.take().expect("MIDI is returned when the engine stops");
+        let controls = Controls {
+            controls,
+            routes,
+            pin_map,
+            leds,
+            _expanders: expanders,
+            state_file,
+            layers: self.config.layers.clone(),
+            banks: self.config.banks.clone(),
+            active: Active { layer: None, bank: (!self.config.banks.is_empty()).then_some(0) },
+            held_shifts: Vec::new(),
+            latched_layer: None,
+        };
+        let event_loop = tokio::spawn(run(controls, rx, midi, shutdown_rx));
+
+        self.running = Some(Running { shutdown, event_loop, poller, stop_threads, threads });
+        Ok(())
+    }
+
+    /// Stop processing events and release all pins. The engine can be started again afterwards.
+    pub async fn stop(&mut self) -> Result<()> {
+        let Some(running) = self.running.take() else {
+            return Ok(());
+        };
+
+        running.poller.abort();
+        running.stop_threads.store(true, Ordering::Relaxed);
+        let _ = running.shutdown.send(());
+        self.midi = Some(running.event_loop.await?);
+        for thread in running.threads {
+            let _ = tokio::task::spawn_blocking(move || thread.join()).await;
+        }
+        Ok(())
+    }
+}
+
+async fn poll_encoders(mut encoders: Vec<PolledEncoder>, tx: mpsc::UnboundedSender<Event>, polling_sleep: Duration) {
+    if encoders.is_empty() {
+        return;
+    }
+
+    loop {
+        for encoder in encoders.iter_mut() {
+            let a = encoder.pin_a.read();
+            let b = encoder.pin_b.read();
+
+            if (a, b) == encoder.levels {
+                continue;
+            }
+            encoder.levels = (a, b);
+
+            if tx.send(Event::EncoderLevels { control: encoder.control, a, b }).is_err() {
+                return;
+            }
+        }
+        sleep(polling_sleep).await;
+    }
+}
+
+/// Call `pass` every `interval` until `stop` is set or it returns false. Blocking, so
+/// for threads of their own rather than the tokio runtime.
+fn every(interval: Duration, stop: &AtomicBool, mut pass: impl FnMut() -> bool) {
+    let mut next_pass = Instant::now();
+    while !stop.load(Ordering::Relaxed) {
+        // Scheduled from the start of each pass so slow reads don't lower the rate
+        next_pass += interval;
+        if !pass() {
+            return;
+        }
+        let now = Instant::now();
+        if next_pass > now {
+            thread::sleep(next_pass - now);
+        } else {
+            // Running behind, so start again from now instead of rushing to catch up
+            next_pass = now;
+        }
+    }
+}
+
+fn read_adc(mut adc: Box<dyn Adc>, mut inputs: Vec<AdcInput>, tx: mpsc::UnboundedSender<Event>, stop: Arc<AtomicBool>, interval: Duration) {
+    every(interval, &stop, || {
+        for input in inputs.iter_mut() {
+            // A failed read is skipped, the next one usually succeeds
+            let Ok(raw) = adc.read(input.input) else { continue };
+            if let Some(value) = input.potentiometer.sample(raw)
+                && tx.send(Event::Potentiometer { control: input.control, value }).is_err()
+            {
+                return false;
+            }
+        }
+        true
+    });
+}
+
+fn scan_matrix(mut scan: MatrixScan, tx: mpsc::UnboundedSender<Event>, stop: Arc<AtomicBool>) {
+    every(scan.interval, &stop, || {
+        let mut raw = Vec::with_capacity(scan.keys.len());
+        for row in scan.rows.iter_mut() {
+            row.set(Some(scan.select));
+            thread::sleep(MATRIX_SETTLE);
+            // Columns are inverted when active low, so read high while pressed either way
+            raw.extend(scan.columns.iter().map(|column| column.read() == Level::High));
+            row.set(None);
+        }
+
+        for (key, pressed) in scan.matrix.scan(&raw, Instant::now()) {
+            if let Some(control) = scan.keys[key]
+                && tx.send(Event::MatrixKey { control, pressed }).is_err()
+            {
+                return false;
+            }
+        }
+        true
+    });
+}
+
+async fn run(mut controls: Controls, mut rx: mpsc::UnboundedReceiver<Event>, mut midi: Midi, mut shutdown: oneshot::Receiver<()>) -> Midi {
+    controls.send_restored(&mut midi.outputs);
+    loop {
+        // Turns, host feedback, and bank and layer changes can all move what a ring shows
+        controls.refresh_rings();
+        let deadline = controls.next_deadline();
+        tokio::select! {
+            _ = &mut shutdown => break,
+            // Closes early if no pins send events, which leaves MIDI input to handle
+            Some(event) = rx.recv() => controls.handle(event, &mut midi.outputs),
+            // The engine holds a sender, so this never closes
+            Some(message) = midi.input.recv() => controls.midi(&message),
+            _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => controls.expire(&mut midi.outputs),
+        }
+    }
+    midi
+}
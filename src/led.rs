@@ -0,0 +1,105 @@
+use crate::backend::{Level, OutputPin, PwmPin};
+use crate::config::{LedConfig, RingConfig, RingStyle};
+use crate::midi::Incoming;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Source {
+    Cc(u8),
+    Note(u8),
+}
+
+#[derive(Debug)]
+enum Drive {
+    // On from the threshold up
+    Switch { pin: Box<dyn OutputPin>, threshold: u8 },
+    // Brightness follows the value
+    Pwm(Box<dyn PwmPin>),
+}
+
+/// An output pin following a CC or note from the MIDI input
+#[derive(Debug)]
+pub(crate) struct Led {
+    drive: Drive,
+    // Zero-based
+    channel: u8,
+    source: Source,
+    active_low: bool,
+}
+
+impl Led {
+    pub fn switched(config: &LedConfig, default_channel: u8, pin: Box<dyn OutputPin>) -> Self {
+        Self::new(config, default_channel, Drive::Switch { pin, threshold: config.threshold })
+    }
+
+    pub fn pwm(config: &LedConfig, default_channel: u8, pin: Box<dyn PwmPin>) -> Self {
+        Self::new(config, default_channel, Drive::Pwm(pin))
+    }
+
+    fn new(config: &LedConfig, default_channel: u8, drive: Drive) -> Self {
+        let source = match (config.cc, config.note) {
+            (_, Some(note)) => Source::Note(note),
+            (cc, None) => Source::Cc(cc.expect("Validated LED has a cc or note")),
+        };
+        let mut led = Self { drive, channel: config.channel.unwrap_or(default_channel) - 1, source, active_low: config.active_low };
+        led.set(0);
+        led
+    }
+
+    /// Follow the message if it's for this LED's CC or note
+    pub fn handle(&mut self, incoming: Incoming) {
+        let value = match (self.source, incoming) {
+            (Source::Cc(cc), Incoming::ControlChange { channel, cc: c, value }) if (channel, c) == (self.channel, cc) => value,
+            (Source::Note(note), Incoming::Note { channel, note: n, velocity }) if (channel, n) == (self.channel, note) => velocity,
+            _ => return,
+        };
+        self.set(value);
+    }
+
+    fn set(&mut self, value: u8) {
+        match &mut self.drive {
+            Drive::Switch { pin, threshold } => {
+                let on = value >= *threshold;
+                pin.write(if on != self.active_low { Level::High } else { Level::Low });
+            }
+            Drive::Pwm(pin) => {
+                let brightness = value as f64 / 127.0;
+                pin.set_duty_cycle(if self.active_low { 1.0 - brightness } else { brightness });
+            }
+        }
+    }
+}
+
+/// LEDs around an encoder showing its value
+#[derive(Debug)]
+pub(crate) struct Ring {
+    pins: Vec<Box<dyn OutputPin>>,
+    style: RingStyle,
+    active_low: bool,
+    // Last state written to each pin, `None` before the first
+    lit: Vec<Option<bool>>,
+}
+
+impl Ring {
+    pub fn new(config: &RingConfig, pins: Vec<Box<dyn OutputPin>>) -> Self {
+        let lit = vec![None; pins.len()];
+        Self { pins, style: config.style, active_low: config.active_low, lit }
+    }
+
+    /// Show `position`, from 0.0 at the lowest value to 1.0 at the highest
+    pub fn show(&mut self, position: f64) {
+        let count = self.pins.len();
+        let n = count as f64;
+        for (index, pin) in self.pins.iter_mut().enumerate() {
+            let on = match self.style {
+                RingStyle::Bar => (index as f64) < (position * n).round(),
+                RingStyle::Dot => index == (position * (n - 1.0)).round() as usize,
+                // Distance of the LED's middle from the ring's middle, against the lit half-width
+                RingStyle::Spread => (index as f64 + 0.5 - n / 2.0).abs() < position * n / 2.0,
+            };
+            if self.lit[index] != Some(on) {
+                self.lit[index] = Some(on);
+                pin.write(if on != self.active_low { Level::High } else { Level::Low });
+            }
+        }
+    }
+}
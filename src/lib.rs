@@ -0,0 +1,15 @@
+pub mod backend;
+mod button;
+pub mod config;
+mod encoder;
+pub mod engine;
+mod led;
+mod mapping;
+mod matrix;
+pub mod midi;
+mod potentiometer;
+mod state;
+
+pub use config::{Config, ControlConfig};
+pub use engine::Engine;
+pub use midi::{MidiSink, Outputs};
@@ -0,0 +1,50 @@
+/// The layer and bank controls are currently mapped through
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Active {
+    pub layer: Option<usize>,
+    pub bank: Option<usize>,
+}
+
+/// A control's mappings: the base one, then one per layer and one per bank, each only
+/// if the control is remapped there
+#[derive(Debug)]
+pub(crate) struct Mappings<T> {
+    slots: Vec<Option<T>>,
+    layers: usize,
+}
+
+impl<T> Mappings<T> {
+    pub fn new(base: Option<T>, layers: Vec<Option<T>>, banks: Vec<Option<T>>) -> Self {
+        let layer_count = layers.len();
+        let slots = std::iter::once(base).chain(layers).chain(banks).collect();
+        Self { slots, layers: layer_count }
+    }
+
+    /// Slot of the mapping in use, preferring the active layer's, then the active bank's
+    pub fn slot(&self, active: Active) -> usize {
+        let layer = active.layer.map(|layer| 1 + layer);
+        let bank = active.bank.map(|bank| 1 + self.layers + bank);
+        [layer, bank].into_iter().flatten().find(|&slot| self.slots[slot].is_some()).unwrap_or(0)
+    }
+
+    pub fn get(&self, slot: usize) -> Option<&T> {
+        self.slots[slot].as_ref()
+    }
+
+    pub fn get_mut(&mut self, slot: usize) -> Option<&mut T> {
+        self.slots[slot].as_mut()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.slots.iter().flatten()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.slots.iter_mut().flatten()
+    }
+
+    /// Slots that have a mapping, with the mapping
+    pub fn slots(&self) -> impl Iterator<Item = (usize, &T)> {
+        self.slots.iter().enumerate().filter_map(|(slot, mapping)| Some((slot, mapping.as_ref()?)))
+    }
+}
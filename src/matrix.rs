@@ -0,0 +1,102 @@
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Debounces the keys of a button matrix and holds back presses that could be ghosts
+#[derive(Debug)]
+pub(crate) struct Matrix {
+    rows: usize,
+    columns: usize,
+    debounce: Duration,
+    detect_ghosts: bool,
+    // Debounced state and time of the last change of each key, row by row
+    pressed: Vec<bool>,
+    changed: Vec<Option<Instant>>,
+}
+
+impl Matrix {
+    pub fn new(rows: usize, columns: usize, debounce: Duration, detect_ghosts: bool) -> Self {
+        Self {
+            rows,
+            columns,
+            debounce,
+            detect_ghosts,
+            pressed: vec![false; rows * columns],
+            changed: vec![None; rows * columns],
+        }
+    }
+
+    /// Feed a full scan, row by row, returning the index and state of each key that changed
+    pub fn scan(&mut self, raw: &[bool], now: Instant) -> Vec<(usize, bool)> {
+        let mut changes = Vec::new();
+        for key in 0..raw.len() {
+            if raw[key] == self.pressed[key] {
+                continue;
+            }
+            if let Some(changed) = self.changed[key]
+                && now.duration_since(changed) < self.debounce
+            {
+                continue;
+            }
+            // Waits for the other keys to be released, after which the press is read if real
+            if raw[key] && self.detect_ghosts && self.could_be_ghost(raw, key) {
+                continue;
+            }
+
+            self.pressed[key] = raw[key];
+            self.changed[key] = Some(now);
+            changes.push((key, raw[key]));
+        }
+        changes
+    }
+
+    /// Without diodes, three pressed corners of a rectangle make the fourth read pressed
+    /// too, so a key can't be told apart from a ghost if it completes a rectangle
+    fn could_be_ghost(&self, raw: &[bool], key: usize) -> bool {
+        let (row, column) = (key / self.columns, key % self.columns);
+        let pressed = |row: usize, column: usize| raw[row * self.columns + column];
+        (0..self.rows).filter(|&other_row| other_row != row && pressed(other_row, column)).any(|other_row| {
+            (0..self.columns).any(|other_column| other_column != column && pressed(row, other_column) && pressed(other_row, other_column))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEBOUNCE: Duration = Duration::from_millis(5);
+
+    // 2x2, keys 0 and 1 on the first row and 2 and 3 on the second
+    fn pressed(keys: &[usize]) -> Vec<bool> {
+        (0..4).map(|key| keys.contains(&key)).collect()
+    }
+
+    #[test]
+    fn fourth_corner_held_back_as_ghost() {
+        let mut matrix = Matrix::new(2, 2, DEBOUNCE, true);
+        let start = Instant::now();
+        assert_eq!(matrix.scan(&pressed(&[0, 1, 2]), start), [(0, true), (1, true), (2, true)]);
+        // Reads pressed through the other three
+        assert_eq!(matrix.scan(&pressed(&[0, 1, 2, 3]), start + DEBOUNCE), []);
+        // Still reading pressed once a corner is released, so it's real
+        assert_eq!(matrix.scan(&pressed(&[1, 2, 3]), start + DEBOUNCE * 2), [(0, false), (3, true)]);
+    }
+
+    #[test]
+    fn fourth_corner_pressed_with_diodes() {
+        let mut matrix = Matrix::new(2, 2, DEBOUNCE, false);
+        let start = Instant::now();
+        assert_eq!(matrix.scan(&pressed(&[0, 1, 2]), start), [(0, true), (1, true), (2, true)]);
+        assert_eq!(matrix.scan(&pressed(&[0, 1, 2, 3]), start + DEBOUNCE), [(3, true)]);
+    }
+
+    #[test]
+    fn bounce_within_debounce_ignored() {
+        let mut matrix = Matrix::new(2, 2, DEBOUNCE, true);
+        let start = Instant::now();
+        assert_eq!(matrix.scan(&pressed(&[0]), start), [(0, true)]);
+        assert_eq!(matrix.scan(&pressed(&[]), start + Duration::from_millis(1)), []);
+        assert_eq!(matrix.scan(&pressed(&[0]), start + Duration::from_millis(2)), []);
+        assert_eq!(matrix.scan(&pressed(&[]), start + DEBOUNCE), [(0, false)]);
+    }
+}
@@ -0,0 +1,175 @@
+use crate::config::{OutputConfig, OutputKind};
+use anyhow::{Context, Result, anyhow};
+use midir::os::unix::VirtualOutput;
+use midir::{MidiIO, MidiOutput, MidiOutputConnection};
+use regex::Regex;
+use rppal::uart::{Parity, Uart};
+use std::path::Path;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Control Change on a zero-based `channel`
+pub fn control_change(channel: u8, cc: u8, value: u8) -> [u8; 3] {
+    [0xB0 | (channel & 0x0F), cc, value]
+}
+
+pub fn note_on(channel: u8, note: u8, velocity: u8) -> [u8; 3] {
+    [0x90 | (channel & 0x0F), note, velocity]
+}
+
+pub fn note_off(channel: u8, note: u8, velocity: u8) -> [u8; 3] {
+    [0x80 | (channel & 0x0F), note, velocity]
+}
+
+/// A received channel message, with a zero-based channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Incoming {
+    ControlChange { channel: u8, cc: u8, value: u8 },
+    /// Note On, or Note Off with a velocity of 0
+    Note { channel: u8, note: u8, velocity: u8 },
+    ProgramChange { channel: u8, program: u8 },
+}
+
+impl Incoming {
+    pub fn parse(message: &[u8]) -> Option<Self> {
+        let (&status, data) = message.split_first()?;
+        let channel = status & 0x0F;
+        Some(match (status & 0xF0, data) {
+            (0xB0, &[cc, value]) => Incoming::ControlChange { channel, cc, value },
+            (0x90, &[note, velocity]) => Incoming::Note { channel, note, velocity },
+            (0x80, &[note, _]) => Incoming::Note { channel, note, velocity: 0 },
+            (0xC0, &[program]) => Incoming::ProgramChange { channel, program },
+            _ => return None,
+        })
+    }
+}
+
+/// Names of the available input or output ports
+pub fn port_names(io: &impl MidiIO) -> Vec<String> {
+    io.ports().iter().filter_map(|port| io.port_name(port).ok()).collect()
+}
+
+/// Find the input or output port whose name contains `pattern`, or failing that matches it as a regex
+pub fn find_port<T: MidiIO>(io: &T, pattern: &str) -> Result<T::Port> {
+    let ports = io.ports();
+    let name = |port: &T::Port| io.port_name(port).unwrap_or_default();
+    if let Some(port) = ports.iter().find(|port| name(port).contains(pattern)) {
+        return Ok(port.clone());
+    }
+
+    let regex = Regex::new(pattern).with_context(|| format!("No MIDI port contains `{pattern}`"))?;
+    ports
+        .into_iter()
+        .find(|port| regex.is_match(&name(port)))
+        .ok_or_else(|| anyhow!("No MIDI port matches `{pattern}`"))
+}
+
+/// Destination for outgoing MIDI messages
+pub trait MidiSink: Send {
+    fn send(&mut self, message: &[u8]) -> Result<()>;
+}
+
+impl MidiSink for MidiOutputConnection {
+    fn send(&mut self, message: &[u8]) -> Result<()> {
+        MidiOutputConnection::send(self, message).map_err(|e| anyhow::anyhow!("{e}"))
+    }
+}
+
+// Lets embedders (and tests) consume messages from a channel
+impl MidiSink for UnboundedSender<Vec<u8>> {
+    fn send(&mut self, message: &[u8]) -> Result<()> {
+        UnboundedSender::send(self, message.to_vec()).map_err(|_| anyhow::anyhow!("MIDI receiver dropped"))
+    }
+}
+
+/// DIN MIDI output from a UART
+pub struct SerialSink {
+    uart: Uart,
+    running_status: bool,
+    // Status byte of the last channel message, left out of the next one if it matches
+    last_status: Option<u8>,
+}
+
+impl SerialSink {
+    pub fn new(path: &Path, baud_rate: u32, running_status: bool) -> Result<Self> {
+        let mut uart = Uart::with_path(path, baud_rate, Parity::None, 8, 1)
+            .with_context(|| format!("Failed to open serial port {}", path.display()))?;
+        // Messages are small, so waiting for room in the output queue beats dropping them
+        uart.set_write_mode(true)?;
+        Ok(Self { uart, running_status, last_status: None })
+    }
+}
+
+impl MidiSink for SerialSink {
+    fn send(&mut self, message: &[u8]) -> Result<()> {
+        let Some(&status) = message.first() else { return Ok(()) };
+        let (data, last_status) = match status {
+            // Channel messages can share the previous status byte
+            0x80..=0xEF if self.running_status && self.last_status == Some(status) => (&message[1..], Some(status)),
+            0x80..=0xEF => (message, Some(status)),
+            // System common messages cancel running status, real-time messages don't
+            0xF0..=0xF7 => (message, None),
+            _ => (message, self.last_status),
+        };
+        // The receiver may have missed part of a failed write, so the next message
+        // sends its status byte again
+        self.last_status = None;
+        self.uart.write(data)?;
+        self.last_status = last_status;
+        Ok(())
+    }
+}
+
+/// Named MIDI outputs that controls are routed to
+#[derive(Default)]
+pub struct Outputs {
+    sinks: Vec<(String, Box<dyn MidiSink>)>,
+}
+
+impl Outputs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Open the configured outputs, using `client_name` for the MIDI client
+    pub fn open(configs: &[OutputConfig], client_name: &str) -> Result<Self> {
+        let mut outputs = Self::new();
+        for config in configs {
+            let name = &config.name;
+            match &config.kind {
+                OutputKind::Virtual { port } => {
+                    let conn = MidiOutput::new(client_name)?
+                        .create_virtual(port.as_deref().unwrap_or(name))
+                        .map_err(|e| anyhow!("Failed to open output `{name}`: {e}"))?;
+                    outputs.add(name, conn);
+                }
+                OutputKind::Port { connect } => {
+                    let midi_out = MidiOutput::new(client_name)?;
+                    let port = find_port(&midi_out, connect)?;
+                    let conn = midi_out.connect(&port, client_name).map_err(|e| anyhow!("Failed to open output `{name}`: {e}"))?;
+                    outputs.add(name, conn);
+                }
+                OutputKind::Serial { path, baud_rate, running_status } => {
+                    outputs.add(name, SerialSink::new(path, *baud_rate, *running_status)?);
+                }
+            }
+        }
+        Ok(outputs)
+    }
+
+    pub fn add(&mut self, name: impl Into<String>, sink: impl MidiSink + 'static) {
+        self.sinks.push((name.into(), Box::new(sink)));
+    }
+
+    pub(crate) fn index(&self, name: &str) -> Option<usize> {
+        self.sinks.iter().position(|(sink_name, _)| sink_name == name)
+    }
+
+    /// Send to the outputs at `routes`, or all of them if empty
+    pub(crate) fn send(&mut self, routes: &[usize], message: &[u8]) {
+        for (index, (_, sink)) in self.sinks.iter_mut().enumerate() {
+            if routes.is_empty() || routes.contains(&index) {
+                let _ = sink.send(message);
+            }
+        }
+    }
+}
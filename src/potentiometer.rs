@@ -0,0 +1,114 @@
+use crate::config::PotentiometerConfig;
+
+/// Turns raw ADC readings into a steady CC value
+#[derive(Debug)]
+pub(crate) struct Potentiometer {
+    raw_min: f64,
+    raw_max: f64,
+    smoothing: f64,
+    deadband: f64,
+    // Moving average of the raw readings
+    average: Option<f64>,
+    value: Option<u8>,
+}
+
+impl Potentiometer {
+    pub fn new(config: &PotentiometerConfig, full_scale: u16) -> Self {
+        Self {
+            raw_min: config.raw_min.unwrap_or(0) as f64,
+            raw_max: config.raw_max.unwrap_or(full_scale) as f64,
+            smoothing: config.smoothing,
+            deadband: config.deadband,
+            average: None,
+            value: None,
+        }
+    }
+
+    /// Feed a raw reading, returning the new CC value if it changed. The first reading
+    /// only sets the starting value.
+    pub fn sample(&mut self, raw: u16) -> Option<u8> {
+        let raw = raw as f64;
+        let average = match self.average {
+            Some(average) => average * self.smoothing + raw * (1.0 - self.smoothing),
+            None => raw,
+        };
+        self.average = Some(average);
+
+        // `raw_min` above `raw_max` reverses the direction
+        let position = ((average - self.raw_min) / (self.raw_max - self.raw_min)).clamp(0.0, 1.0) * 127.0;
+        let next = position.round() as u8;
+        let Some(value) = self.value else {
+            self.value = Some(next);
+            return None;
+        };
+
+        // The ends can't be reached past the deadband, so take them as soon as they round
+        let past_deadband = (position - value as f64).abs() >= 0.5 + self.deadband;
+        if next != value && (past_deadband || next == 0 || next == 127) {
+            self.value = Some(next);
+            Some(next)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A full scale of 1270 makes every 10 raw one CC step
+    fn potentiometer(smoothing: f64, deadband: f64, raw_min: Option<u16>, raw_max: Option<u16>) -> Potentiometer {
+        let config = PotentiometerConfig {
+            adc: "adc".to_string(),
+            input: 0,
+            cc: 1,
+            channel: None,
+            raw_min,
+            raw_max,
+            smoothing,
+            deadband,
+            layers: Default::default(),
+            banks: Default::default(),
+            outputs: Vec::new(),
+        };
+        Potentiometer::new(&config, 1270)
+    }
+
+    #[test]
+    fn smoothing_averages_readings() {
+        let mut pot = potentiometer(0.5, 0.0, None, None);
+        assert_eq!(pot.sample(0), None);
+        assert_eq!(pot.sample(1270), Some(64));
+        assert_eq!(pot.sample(1270), Some(95));
+    }
+
+    #[test]
+    fn deadband_holds_value() {
+        let mut pot = potentiometer(0.0, 0.5, None, None);
+        assert_eq!(pot.sample(640), None);
+        // Rounds to 65, but within the deadband
+        assert_eq!(pot.sample(645), None);
+        assert_eq!(pot.sample(650), Some(65));
+        assert_eq!(pot.sample(645), None);
+        assert_eq!(pot.sample(636), Some(64));
+    }
+
+    #[test]
+    fn ends_reachable_within_deadband() {
+        let mut pot = potentiometer(0.0, 2.0, None, None);
+        assert_eq!(pot.sample(1250), None);
+        assert_eq!(pot.sample(1265), Some(127));
+        assert_eq!(pot.sample(10), Some(1));
+        assert_eq!(pot.sample(4), Some(0));
+    }
+
+    #[test]
+    fn raw_min_above_raw_max_reverses() {
+        let mut pot = potentiometer(0.0, 0.0, Some(1270), Some(0));
+        assert_eq!(pot.sample(0), None);
+        assert_eq!(pot.sample(1270), Some(0));
+        assert_eq!(pot.sample(0), Some(127));
+        assert_eq!(pot.sample(635), Some(64));
+    }
+}
@@ -0,0 +1,42 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SavedState {
+    /// Latched toggle buttons by pin, `row_pin`x`column_pin` for matrix keys, with
+    /// `/layer` or `/bank` added for their mappings
+    #[serde(default)]
+    toggles: BTreeMap<String, bool>,
+}
+
+/// Control state persisted across restarts
+#[derive(Debug)]
+pub(crate) struct StateFile {
+    path: PathBuf,
+    state: SavedState,
+}
+
+impl StateFile {
+    /// Load `path`, starting empty if it doesn't exist yet
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let state = match fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => SavedState::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self { path, state })
+    }
+
+    pub fn toggle(&self, key: &str) -> Option<bool> {
+        self.state.toggles.get(key).copied()
+    }
+
+    pub fn set_toggle(&mut self, key: &str, latched: bool) -> Result<()> {
+        self.state.toggles.insert(key.to_string(), latched);
+        fs::write(&self.path, toml::to_string(&self.state)?)?;
+        Ok(())
+    }
+}
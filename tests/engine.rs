@@ -0,0 +1,680 @@
+use gpio2midi::backend::{Level, MockBackend};
+use gpio2midi::{Config, Engine, Outputs};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+fn start(config: &str) -> (Engine, MockBackend, UnboundedReceiver<Vec<u8>>) {
+    let config: Config = config.parse().unwrap();
+    let backend = MockBackend::new();
+    let (tx, rx) = mpsc::unbounded_channel();
+    let mut engine = Engine::new(config, backend.clone(), tx);
+    engine.start().unwrap();
+    (engine, backend, rx)
+}
+
+/// Let the event loop catch up, then take everything it sent
+async fn sent(rx: &mut UnboundedReceiver<Vec<u8>>) -> Vec<Vec<u8>> {
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    let mut messages = Vec::new();
+    while let Ok(message) = rx.try_recv() {
+        messages.push(message);
+    }
+    messages
+}
+
+fn level(high: bool) -> Level {
+    if high { Level::High } else { Level::Low }
+}
+
+/// Turn an encoder on `pin_a`/`pin_b`, pulled up so resting at A and B high, by one full-step detent
+fn turn(backend: &MockBackend, pin_a: u8, pin_b: u8, up: bool) {
+    let sequence = if up {
+        [(true, false), (false, false), (false, true), (true, true)]
+    } else {
+        [(false, true), (false, false), (true, false), (true, true)]
+    };
+    for (a, b) in sequence {
+        backend.set_level(pin_a, level(a));
+        backend.set_level(pin_b, level(b));
+    }
+}
+
+#[tokio::test]
+async fn button_press_and_release() {
+    let (mut engine, backend, mut rx) = start(
+        r#"
+        [[controls]]
+        type = "Button"
+        pin = 17
+        cc = 20
+        "#,
+    );
+
+    // Pulled up, so pressed pulls the pin low
+    backend.set_level(17, Level::Low);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 20, 127]]);
+    backend.set_level(17, Level::High);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 20, 0]]);
+    engine.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn button_note_on_channel() {
+    let (mut engine, backend, mut rx) = start(
+        r#"
+        [[controls]]
+        type = "Button"
+        pin = 17
+        note = 60
+        velocity = 100
+        channel = 3
+        "#,
+    );
+
+    backend.set_level(17, Level::Low);
+    backend.set_level(17, Level::High);
+    assert_eq!(sent(&mut rx).await, [vec![0x92, 60, 100], vec![0x82, 60, 0]]);
+    engine.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn encoder_absolute() {
+    let (mut engine, backend, mut rx) = start(
+        r#"
+        [[controls]]
+        type = "RotaryEncoder"
+        pin_a = 20
+        pin_b = 21
+        cc = 7
+        "#,
+    );
+
+    turn(&backend, 20, 21, true);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 7, 65]]);
+    turn(&backend, 20, 21, false);
+    turn(&backend, 20, 21, false);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 7, 64], vec![0xB0, 7, 63]]);
+    engine.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn encoder_absolute_stops_at_max() {
+    let (mut engine, backend, mut rx) = start(
+        r#"
+        [[controls]]
+        type = "RotaryEncoder"
+        pin_a = 20
+        pin_b = 21
+        cc = 7
+        max = 65
+        "#,
+    );
+
+    turn(&backend, 20, 21, true);
+    turn(&backend, 20, 21, true);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 7, 65]]);
+    engine.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn encoder_relative() {
+    let (mut engine, backend, mut rx) = start(
+        r#"
+        [[controls]]
+        type = "RotaryEncoder"
+        pin_a = 20
+        pin_b = 21
+        cc = 7
+        relative_value = true
+        "#,
+    );
+
+    turn(&backend, 20, 21, true);
+    turn(&backend, 20, 21, false);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 7, 1], vec![0xB0, 7, 127]]);
+    engine.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn encoder_burst_keeps_every_detent() {
+    let (mut engine, backend, mut rx) = start(
+        r#"
+        [[controls]]
+        type = "RotaryEncoder"
+        pin_a = 20
+        pin_b = 21
+        cc = 7
+        relative_value = true
+        "#,
+    );
+
+    // Far more edges than the event loop takes in before it next runs
+    for _ in 0..200 {
+        turn(&backend, 20, 21, true);
+    }
+    assert_eq!(sent(&mut rx).await, vec![vec![0xB0, 7, 1]; 200]);
+    engine.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn encoder_relative_binary_offset() {
+    let (mut engine, backend, mut rx) = start(
+        r#"
+        [[controls]]
+        type = "RotaryEncoder"
+        pin_a = 20
+        pin_b = 21
+        cc = 7
+        relative_value = true
+        relative_encoding = "binary_offset"
+        "#,
+    );
+
+    turn(&backend, 20, 21, true);
+    turn(&backend, 20, 21, false);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 7, 65], vec![0xB0, 7, 63]]);
+    engine.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn encoder_value_shared_across_banks_unless_remapped() {
+    let (mut engine, backend, mut rx) = start(
+        r#"
+        [[banks]]
+        name = "a"
+
+        [[banks]]
+        name = "b"
+
+        [[banks]]
+        name = "c"
+
+        [[controls]]
+        type = "RotaryEncoder"
+        pin_a = 20
+        pin_b = 21
+        cc = 7
+        initial_value = 100
+        banks = { c = { cc = 8 } }
+        "#,
+    );
+    let midi_input = engine.midi_input();
+
+    turn(&backend, 20, 21, false);
+    turn(&backend, 20, 21, false);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 7, 99], vec![0xB0, 7, 98]]);
+
+    // Bank `b` doesn't remap the encoder, so it carries on from the same value
+    midi_input.send(vec![0xC0, 1]).unwrap();
+    assert!(sent(&mut rx).await.is_empty());
+    turn(&backend, 20, 21, false);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 7, 97]]);
+
+    // Bank `c` remaps it and has a value of its own
+    midi_input.send(vec![0xC0, 2]).unwrap();
+    assert!(sent(&mut rx).await.is_empty());
+    turn(&backend, 20, 21, true);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 8, 101]]);
+
+    midi_input.send(vec![0xC0, 0]).unwrap();
+    assert!(sent(&mut rx).await.is_empty());
+    turn(&backend, 20, 21, true);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 7, 98]]);
+    engine.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn encoder_switch_pulled_down_is_active_high() {
+    let (mut engine, backend, mut rx) = start(
+        r#"
+        [[controls]]
+        type = "RotaryEncoder"
+        pin_a = 20
+        pin_b = 21
+        cc = 7
+        pin_switch = 22
+        switch_pull = "down"
+        switch = { cc = 20 }
+        "#,
+    );
+
+    backend.set_level(22, Level::High);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 20, 127]]);
+    backend.set_level(22, Level::Low);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 20, 0]]);
+    engine.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn matrix_key_press_and_release() {
+    let (mut engine, backend, mut rx) = start(
+        r#"
+        [[controls]]
+        type = "ButtonMatrix"
+        rows = [5, 6]
+        columns = [12, 13]
+        keys = [
+          { row = 0, column = 1, note = 37 },
+          { row = 1, column = 0, cc = 20 },
+        ]
+        "#,
+    );
+
+    backend.set_connected(6, 12, true);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 20, 127]]);
+    backend.set_connected(6, 12, false);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 20, 0]]);
+    backend.set_connected(5, 13, true);
+    assert_eq!(sent(&mut rx).await, [vec![0x90, 37, 127]]);
+    engine.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn potentiometer_sends_on_change() {
+    let (mut engine, backend, mut rx) = start(
+        r#"
+        [[adcs]]
+        name = "faders"
+        type = "mcp3008"
+        slave_select = 1
+
+        [[controls]]
+        type = "Potentiometer"
+        adc = "faders"
+        input = 2
+        cc = 7
+        smoothing = 0.0
+        deadband = 0.0
+        "#,
+    );
+
+    // The first reading only sets where the fader starts
+    assert!(sent(&mut rx).await.is_empty());
+    backend.set_analog("faders", 2, 1023);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 7, 127]]);
+    backend.set_analog("faders", 2, 512);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 7, 64]]);
+    engine.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn expander_pins_read_on_interrupt() {
+    let (mut engine, backend, mut rx) = start(
+        r#"
+        [[expanders]]
+        name = "pads"
+        type = "mcp23017"
+        interrupt = 4
+
+        [[controls]]
+        type = "Button"
+        pin = "pads:A0"
+        cc = 20
+
+        [[controls]]
+        type = "RotaryEncoder"
+        pin_a = "pads:B0"
+        pin_b = "pads:B1"
+        cc = 7
+        relative_value = true
+        "#,
+    );
+
+    backend.set_expander_level("pads", 0, Level::Low);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 20, 127]]);
+    backend.set_expander_level("pads", 0, Level::High);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 20, 0]]);
+
+    for (a, b) in [(true, false), (false, false), (false, true), (true, true)] {
+        backend.set_expander_level("pads", 8, level(a));
+        backend.set_expander_level("pads", 9, level(b));
+    }
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 7, 1]]);
+    engine.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn leds_follow_midi_input() {
+    let (mut engine, backend, mut rx) = start(
+        r#"
+        controls = []
+
+        [[leds]]
+        pin = 25
+        cc = 20
+
+        [[leds]]
+        pin = 18
+        cc = 21
+        pwm = true
+        "#,
+    );
+    let input = engine.midi_input();
+
+    assert_eq!(backend.level(25), Some(Level::Low));
+    assert_eq!(backend.duty_cycle(18), Some(0.0));
+    input.send(vec![0xB0, 20, 64]).unwrap();
+    input.send(vec![0xB0, 21, 127]).unwrap();
+    assert!(sent(&mut rx).await.is_empty());
+    assert_eq!(backend.level(25), Some(Level::High));
+    assert_eq!(backend.duty_cycle(18), Some(1.0));
+
+    input.send(vec![0xB0, 20, 63]).unwrap();
+    input.send(vec![0xB0, 21, 0]).unwrap();
+    sent(&mut rx).await;
+    assert_eq!(backend.level(25), Some(Level::Low));
+    assert_eq!(backend.duty_cycle(18), Some(0.0));
+    engine.stop().await.unwrap();
+}
+
+/// Press and release a button pulled up on `pin`
+fn tap(backend: &MockBackend, pin: u8) {
+    backend.set_level(pin, Level::Low);
+    backend.set_level(pin, Level::High);
+}
+
+const SHIFTED_BUTTON: &str = r#"
+    layers = ["a", "b"]
+
+    [[controls]]
+    type = "Button"
+    pin = 18
+    cc = 10
+    layers = { a = { cc = 11 }, b = { cc = 12 } }
+    "#;
+
+#[tokio::test]
+async fn overlapping_momentary_shifts() {
+    let (mut engine, backend, mut rx) = start(&format!(
+        r#"{SHIFTED_BUTTON}
+        [[controls]]
+        type = "Button"
+        pin = 16
+        action = {{ type = "shift", layer = "a" }}
+
+        [[controls]]
+        type = "Button"
+        pin = 17
+        action = {{ type = "shift", layer = "b" }}
+        "#
+    ));
+
+    backend.set_level(16, Level::Low);
+    backend.set_level(17, Level::Low);
+    tap(&backend, 18);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 12, 127], vec![0xB0, 12, 0]]);
+
+    // Still held, so "b" stays ahead of it
+    backend.set_level(16, Level::High);
+    tap(&backend, 18);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 12, 127], vec![0xB0, 12, 0]]);
+
+    backend.set_level(17, Level::High);
+    tap(&backend, 18);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 10, 127], vec![0xB0, 10, 0]]);
+
+    // Released in the order they were pressed
+    backend.set_level(16, Level::Low);
+    backend.set_level(17, Level::Low);
+    backend.set_level(17, Level::High);
+    tap(&backend, 18);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 11, 127], vec![0xB0, 11, 0]]);
+    backend.set_level(16, Level::High);
+    tap(&backend, 18);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 10, 127], vec![0xB0, 10, 0]]);
+    engine.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn latching_shift_under_momentary_shift() {
+    let (mut engine, backend, mut rx) = start(&format!(
+        r#"{SHIFTED_BUTTON}
+        [[controls]]
+        type = "Button"
+        pin = 16
+        action = {{ type = "shift", layer = "a", latching = true }}
+
+        [[controls]]
+        type = "Button"
+        pin = 17
+        action = {{ type = "shift", layer = "b" }}
+        "#
+    ));
+
+    tap(&backend, 16);
+    tap(&backend, 18);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 11, 127], vec![0xB0, 11, 0]]);
+
+    backend.set_level(17, Level::Low);
+    tap(&backend, 18);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 12, 127], vec![0xB0, 12, 0]]);
+
+    // Back to the latched layer
+    backend.set_level(17, Level::High);
+    tap(&backend, 18);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 11, 127], vec![0xB0, 11, 0]]);
+
+    tap(&backend, 16);
+    tap(&backend, 18);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 10, 127], vec![0xB0, 10, 0]]);
+    engine.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn potentiometer_follows_layer() {
+    let (mut engine, backend, mut rx) = start(
+        r#"
+        layers = ["fx"]
+
+        [[adcs]]
+        name = "faders"
+        type = "mcp3008"
+
+        [[controls]]
+        type = "Potentiometer"
+        adc = "faders"
+        input = 0
+        cc = 7
+        smoothing = 0.0
+        layers = { fx = { cc = 30, channel = 2 } }
+
+        [[controls]]
+        type = "Button"
+        pin = 16
+        action = { type = "shift", layer = "fx" }
+        "#,
+    );
+
+    assert!(sent(&mut rx).await.is_empty());
+    backend.set_analog("faders", 0, 1023);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 7, 127]]);
+    backend.set_level(16, Level::Low);
+    backend.set_analog("faders", 0, 0);
+    assert_eq!(sent(&mut rx).await, [vec![0xB1, 30, 0]]);
+    engine.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn encoder_follows_incoming_cc_within_range() {
+    let (mut engine, backend, mut rx) = start(
+        r#"
+        [[controls]]
+        type = "RotaryEncoder"
+        pin_a = 20
+        pin_b = 21
+        cc = 7
+        min = 10
+        max = 100
+        "#,
+    );
+    let input = engine.midi_input();
+
+    for (incoming, up, next) in [
+        (vec![0xB0, 7, 40], true, 41),
+        // Clamped to `max` and `min`
+        (vec![0xB0, 7, 120], false, 99),
+        (vec![0xB0, 7, 0], true, 11),
+        // Another channel and CC are left alone
+        (vec![0xB1, 7, 50], true, 12),
+        (vec![0xB0, 8, 50], true, 13),
+    ] {
+        input.send(incoming).unwrap();
+        assert!(sent(&mut rx).await.is_empty());
+        turn(&backend, 20, 21, up);
+        assert_eq!(sent(&mut rx).await, [vec![0xB0, 7, next]]);
+    }
+    engine.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn relative_encoder_ignores_incoming_cc() {
+    let (mut engine, backend, mut rx) = start(
+        r#"
+        [[controls]]
+        type = "RotaryEncoder"
+        pin_a = 20
+        pin_b = 21
+        cc = 7
+        relative_value = true
+        "#,
+    );
+
+    engine.midi_input().send(vec![0xB0, 7, 100]).unwrap();
+    assert!(sent(&mut rx).await.is_empty());
+    turn(&backend, 20, 21, true);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 7, 1]]);
+    engine.stop().await.unwrap();
+}
+
+/// A state file path unique to the test, removed if left over from an earlier run
+fn state_file(test: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("gpio2midi-{test}-{}.toml", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+fn saved_toggle(path: &std::path::Path, key: &str) -> Option<bool> {
+    let state: toml::Table = std::fs::read_to_string(path).ok()?.parse().unwrap();
+    state.get("toggles")?.get(key)?.as_bool()
+}
+
+fn restored_toggle(path: &std::path::Path) -> String {
+    format!(
+        r#"
+        state_file = '{}'
+
+        [[controls]]
+        type = "Button"
+        pin = 17
+        cc = 20
+        mode = "toggle"
+        restore = true
+        "#,
+        path.display()
+    )
+}
+
+#[tokio::test]
+async fn toggle_follows_incoming_cc_and_saves_it() {
+    let path = state_file("toggle-feedback");
+    let (mut engine, backend, mut rx) = start(&restored_toggle(&path));
+    let input = engine.midi_input();
+    // Restored toggles are sent on startup, off if never saved
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 20, 0]]);
+
+    input.send(vec![0xB0, 20, 127]).unwrap();
+    assert!(sent(&mut rx).await.is_empty());
+    assert_eq!(saved_toggle(&path, "17"), Some(true));
+    // Already on, so pressing turns it off
+    backend.set_level(17, Level::Low);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 20, 0]]);
+    assert_eq!(saved_toggle(&path, "17"), Some(false));
+
+    // Any value but `off_value` is on
+    input.send(vec![0xB0, 20, 1]).unwrap();
+    assert!(sent(&mut rx).await.is_empty());
+    assert_eq!(saved_toggle(&path, "17"), Some(true));
+    engine.stop().await.unwrap();
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn controls_reach_their_outputs() {
+    let config: Config = r#"
+        [[controls]]
+        type = "Button"
+        pin = 16
+        cc = 20
+        outputs = ["b"]
+
+        [[controls]]
+        type = "ButtonMatrix"
+        rows = [5, 6]
+        columns = [12, 13]
+        keys = [
+          { row = 0, column = 0, cc = 30, outputs = ["a"] },
+          { row = 1, column = 1, cc = 31 },
+        ]
+
+        [[controls]]
+        type = "Button"
+        pin = 17
+        cc = 40
+        outputs = ["a"]
+        "#
+    .parse()
+    .unwrap();
+    let backend = MockBackend::new();
+    let (a, mut rx_a) = mpsc::unbounded_channel();
+    let (b, mut rx_b) = mpsc::unbounded_channel();
+    let mut outputs = Outputs::new();
+    outputs.add("a", a);
+    outputs.add("b", b);
+    let mut engine = Engine::with_outputs(config, backend.clone(), outputs);
+    engine.start().unwrap();
+
+    backend.set_level(16, Level::Low);
+    assert!(sent(&mut rx_a).await.is_empty());
+    assert_eq!(sent(&mut rx_b).await, [vec![0xB0, 20, 127]]);
+
+    backend.set_connected(5, 12, true);
+    assert_eq!(sent(&mut rx_a).await, [vec![0xB0, 30, 127]]);
+    assert!(sent(&mut rx_b).await.is_empty());
+
+    // No `outputs`, so every output
+    backend.set_connected(6, 13, true);
+    assert_eq!(sent(&mut rx_a).await, [vec![0xB0, 31, 127]]);
+    assert_eq!(sent(&mut rx_b).await, [vec![0xB0, 31, 127]]);
+
+    backend.set_level(17, Level::Low);
+    assert_eq!(sent(&mut rx_a).await, [vec![0xB0, 40, 127]]);
+    assert!(sent(&mut rx_b).await.is_empty());
+    engine.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn toggle_restored_on_next_start() {
+    let path = state_file("toggle-restore");
+    let (mut engine, backend, mut rx) = start(&restored_toggle(&path));
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 20, 0]]);
+
+    // Latches on the press, the release sends nothing
+    backend.set_level(17, Level::Low);
+    backend.set_level(17, Level::High);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 20, 127]]);
+    assert_eq!(saved_toggle(&path, "17"), Some(true));
+
+    engine.stop().await.unwrap();
+    engine.start().unwrap();
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 20, 127]]);
+    engine.stop().await.unwrap();
+
+    // A new engine reads it from the file too, and carries on from it
+    let (mut engine, backend, mut rx) = start(&restored_toggle(&path));
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 20, 127]]);
+    backend.set_level(17, Level::Low);
+    assert_eq!(sent(&mut rx).await, [vec![0xB0, 20, 0]]);
+    assert_eq!(saved_toggle(&path, "17"), Some(false));
+    engine.stop().await.unwrap();
+    let _ = std::fs::remove_file(&path);
+}